@@ -0,0 +1,124 @@
+use crate::auth::Grant;
+use crate::pubsub::{full_state_payload, UpdateFeed};
+use crate::websocket::WsState;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde_json::json;
+use tracing::{error, info};
+
+/// Handler for GET /admin/matches
+/// Matches with a broadcast channel, how many are subscribed here and how
+/// many are watching across the cluster
+pub async fn list_matches(State(state): State<WsState>) -> Json<serde_json::Value> {
+    let mut matches = state.match_stats().await;
+    for stats in &mut matches {
+        stats.cluster_viewers = state.redis.get_viewers(&stats.match_id).await.ok();
+    }
+    Json(json!(matches))
+}
+
+/// Handler for GET /admin/nodes
+/// Backend nodes that have reported presence recently
+pub async fn list_nodes(State(state): State<WsState>) -> Response {
+    match state.redis.get_nodes().await {
+        Ok(nodes) => Json(json!(nodes)).into_response(),
+        Err(e) => {
+            error!("Failed to list nodes: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Internal server error",
+                    "message": "Failed to fetch nodes"
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Handler for GET /admin/connections
+pub async fn list_connections(State(state): State<WsState>) -> Json<serde_json::Value> {
+    Json(json!(state.connection_infos().await))
+}
+
+/// Handler for GET /admin/connections/:id
+pub async fn get_connection(Path(id): Path<u64>, State(state): State<WsState>) -> Response {
+    match state.connection_info(id).await {
+        Some(info) => Json(json!(info)).into_response(),
+        None => connection_not_found(id),
+    }
+}
+
+/// Handler for DELETE /admin/connections/:id
+/// Closes the WebSocket with code 1008; the client may reconnect
+pub async fn close_connection(
+    Path(id): Path<u64>,
+    Extension(grant): Extension<Grant>,
+    State(state): State<WsState>,
+) -> Response {
+    info!("Admin {} closing connection {}", grant.name, id);
+    if state.close_connection(id, "closed by administrator").await {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        connection_not_found(id)
+    }
+}
+
+/// Handler for POST /admin/matches/:match_id/rebroadcast
+/// Publishes an update asking every node to re-read the full state from
+/// Redis and send it to its subscribers
+pub async fn rebroadcast_match(
+    Path(match_id): Path<String>,
+    Extension(grant): Extension<Grant>,
+    Extension(feed): Extension<UpdateFeed>,
+    State(state): State<WsState>,
+) -> Response {
+    info!("Admin {} rebroadcasting match {}", grant.name, match_id);
+    let published = match state.redis.match_exists(&match_id).await {
+        Ok(true) => {
+            feed.publish(&state.redis, &match_id, &full_state_payload())
+                .await
+        }
+        Ok(false) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "error": "Match not found",
+                    "match_id": match_id
+                })),
+            )
+                .into_response()
+        }
+        Err(e) => Err(e),
+    };
+
+    match published {
+        Ok(()) => (StatusCode::ACCEPTED, Json(json!({ "match_id": match_id }))).into_response(),
+        Err(e) => {
+            error!("Failed to rebroadcast match {}: {}", match_id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Internal server error",
+                    "message": "Failed to fetch match data"
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+fn connection_not_found(id: u64) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({
+            "error": "Connection not found",
+            "id": id
+        })),
+    )
+        .into_response()
+}
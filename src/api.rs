@@ -1,11 +1,14 @@
+use crate::auth::Grant;
 use crate::redis_client::RedisClient;
-use axum::{extract::State, http::StatusCode, Json};
+use axum::{extract::State, http::StatusCode, Extension, Json};
 use serde_json::json;
 use tracing::error;
 
 /// Handler for GET /api/matches/live
 /// Protected with panic recovery to ensure no crashes
+/// Only lists matches the client's credentials allow
 pub async fn get_live_matches(
+    Extension(grant): Extension<Grant>,
     State(redis): State<RedisClient>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, Json<serde_json::Value>)> {
     // Wrap in panic recovery
@@ -26,7 +29,10 @@ pub async fn get_live_matches(
     };
 
     match redis.get_live_matches().await {
-        Ok(matches) => Ok(Json(json!(matches))),
+        Ok(mut matches) => {
+            matches.retain(|summary| grant.allows_summary(summary));
+            Ok(Json(json!(matches)))
+        }
         Err(e) => {
             error!("Failed to get live matches: {}", e);
             Err((
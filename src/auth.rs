@@ -0,0 +1,449 @@
+use crate::models::MatchSummary;
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+/// Where credentials are read from
+#[derive(Debug, Clone, Default)]
+pub struct AuthSettings {
+    /// Shared secret for HS256 tokens
+    pub jwt_secret: Option<String>,
+    /// PEM file with the public key for RS256 tokens
+    pub jwt_public_key_file: Option<String>,
+    /// Required `iss` claim, if set
+    pub jwt_issuer: Option<String>,
+    /// Required `aud` claim, if set
+    pub jwt_audience: Option<String>,
+    /// JSON file mapping API keys to what they may access
+    pub api_keys_file: Option<String>,
+}
+
+/// What an authenticated client may access
+///
+/// A client is allowed a match if it is listed in `matches` or belongs to
+/// one of the `tournaments`. With neither set, every match is allowed.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "GrantClaims")]
+pub struct Grant {
+    /// Token `name` (else `sub`) or API key name, for logs
+    pub name: String,
+    matches: Option<HashSet<String>>,
+    tournaments: Option<HashSet<String>>,
+    /// May use the admin routes
+    admin: bool,
+    /// No credentials were checked
+    anonymous: bool,
+}
+
+/// Grant as written in a token or the API keys file
+#[derive(Deserialize)]
+struct GrantClaims {
+    #[serde(default)]
+    name: Option<String>,
+    /// Standard JWT subject, used when there is no `name`
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    matches: Option<HashSet<String>>,
+    #[serde(default)]
+    tournaments: Option<HashSet<String>>,
+    #[serde(default)]
+    admin: bool,
+}
+
+impl From<GrantClaims> for Grant {
+    fn from(claims: GrantClaims) -> Self {
+        Self {
+            name: claims.name.or(claims.sub).unwrap_or_default(),
+            matches: claims.matches,
+            tournaments: claims.tournaments,
+            admin: claims.admin,
+            anonymous: false,
+        }
+    }
+}
+
+impl Grant {
+    /// Access to everything, used when authentication is off
+    fn anonymous() -> Self {
+        Self {
+            name: "anonymous".to_string(),
+            anonymous: true,
+            ..Self::default()
+        }
+    }
+
+    /// Whether authentication is off and this grant wasn't given by a credential
+    pub fn is_anonymous(&self) -> bool {
+        self.anonymous
+    }
+
+    /// Whether the admin routes may be used; never true without authentication
+    pub fn is_admin(&self) -> bool {
+        self.admin && !self.anonymous
+    }
+
+    /// Whether this grant limits access by tournament
+    pub fn restricts_tournaments(&self) -> bool {
+        self.tournaments.is_some()
+    }
+
+    /// Whether a match in the given tournament (if known) may be accessed
+    pub fn allows(&self, match_id: &str, tournament_id: Option<&str>) -> bool {
+        if self.matches.is_none() && self.tournaments.is_none() {
+            return true;
+        }
+
+        let by_match = self
+            .matches
+            .as_ref()
+            .is_some_and(|matches| matches.contains(match_id));
+        let by_tournament = match (&self.tournaments, tournament_id) {
+            (Some(tournaments), Some(tournament_id)) => tournaments.contains(tournament_id),
+            _ => false,
+        };
+        by_match || by_tournament
+    }
+
+    /// Whether a live matches list entry may be shown
+    pub fn allows_summary(&self, summary: &MatchSummary) -> bool {
+        self.allows(&summary.match_id, summary.tournament_id.as_deref())
+    }
+}
+
+/// Why a request was refused
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Missing credentials")]
+    MissingCredentials,
+    #[error("Invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+    #[error("Token algorithm {0:?} is not accepted")]
+    UnsupportedAlgorithm(Algorithm),
+    #[error("Unknown API key")]
+    UnknownApiKey,
+}
+
+/// Credentials passed in the query string, for clients that can't set
+/// headers (browser WebSockets, EventSource)
+#[derive(Debug, Deserialize)]
+struct AuthParams {
+    access_token: Option<String>,
+    api_key: Option<String>,
+}
+
+/// Checks JWTs and API keys; open to everyone when nothing is configured
+#[derive(Clone)]
+pub struct Authenticator {
+    keys: Option<Arc<Keys>>,
+}
+
+struct Keys {
+    /// Decoding key and rules per accepted algorithm
+    jwt: Vec<(DecodingKey, Validation)>,
+    /// API key -> grant
+    api_keys: HashMap<String, Grant>,
+}
+
+impl Authenticator {
+    /// Load keys; authentication is required if any are configured
+    pub fn new(settings: &AuthSettings) -> Result<Self> {
+        let mut jwt = Vec::new();
+
+        if let Some(secret) = &settings.jwt_secret {
+            let key = DecodingKey::from_secret(secret.as_bytes());
+            jwt.push((key, validation(Algorithm::HS256, settings)));
+        }
+
+        if let Some(path) = &settings.jwt_public_key_file {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read JWT public key {path}"))?;
+            let key = DecodingKey::from_rsa_pem(&pem)
+                .with_context(|| format!("Invalid RSA public key in {path}"))?;
+            jwt.push((key, validation(Algorithm::RS256, settings)));
+        }
+
+        let api_keys = match &settings.api_keys_file {
+            Some(path) => {
+                let data = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read API keys file {path}"))?;
+                serde_json::from_str(&data)
+                    .with_context(|| format!("Invalid API keys file {path}"))?
+            }
+            None => HashMap::new(),
+        };
+
+        if jwt.is_empty() && api_keys.is_empty() {
+            return Ok(Self { keys: None });
+        }
+        Ok(Self {
+            keys: Some(Arc::new(Keys { jwt, api_keys })),
+        })
+    }
+
+    /// Whether requests need credentials
+    pub fn is_enabled(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    /// Work out what a request may access from its credentials
+    ///
+    /// JWTs come from `Authorization: Bearer` or `?access_token=`, API keys
+    /// from `X-API-Key` or `?api_key=`.
+    pub fn authenticate(&self, headers: &HeaderMap, uri: &Uri) -> Result<Grant, AuthError> {
+        let Some(keys) = &self.keys else {
+            return Ok(Grant::anonymous());
+        };
+
+        let params = Query::<AuthParams>::try_from_uri(uri)
+            .ok()
+            .map(|Query(params)| params);
+
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string)
+            .or_else(|| params.as_ref().and_then(|p| p.access_token.clone()));
+        if let Some(token) = bearer {
+            return keys.verify_jwt(&token);
+        }
+
+        let api_key = headers
+            .get("x-api-key")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| params.and_then(|p| p.api_key));
+        if let Some(api_key) = api_key {
+            return keys
+                .api_keys
+                .get(&api_key)
+                .cloned()
+                .ok_or(AuthError::UnknownApiKey);
+        }
+
+        Err(AuthError::MissingCredentials)
+    }
+}
+
+impl Keys {
+    fn verify_jwt(&self, token: &str) -> Result<Grant, AuthError> {
+        let alg = jsonwebtoken::decode_header(token)?.alg;
+        let (key, validation) = self
+            .jwt
+            .iter()
+            .find(|(_, validation)| validation.algorithms.contains(&alg))
+            .ok_or(AuthError::UnsupportedAlgorithm(alg))?;
+
+        let data = jsonwebtoken::decode::<Grant>(token, key, validation)?;
+        Ok(data.claims)
+    }
+}
+
+/// Token checks for one algorithm
+fn validation(alg: Algorithm, settings: &AuthSettings) -> Validation {
+    let mut validation = Validation::new(alg);
+    if let Some(issuer) = &settings.jwt_issuer {
+        validation.set_issuer(&[issuer]);
+    }
+    match &settings.jwt_audience {
+        Some(audience) => validation.set_audience(&[audience]),
+        // Otherwise tokens that carry an `aud` would all be rejected
+        None => validation.validate_aud = false,
+    }
+    validation
+}
+
+/// Middleware rejecting requests without valid credentials
+///
+/// The client's `Grant` is added to the request extensions for handlers.
+pub async fn require_auth(
+    State(auth): State<Authenticator>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    match auth.authenticate(req.headers(), req.uri()) {
+        Ok(grant) => {
+            debug!("Authenticated request as {}", grant.name);
+            req.extensions_mut().insert(grant);
+            next.run(req).await
+        }
+        Err(e) => {
+            warn!(
+                "Rejected unauthenticated request to {}: {}",
+                req.uri().path(),
+                e
+            );
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "error": "Unauthorized",
+                    "message": e.to_string()
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Middleware limiting routes to admin credentials
+///
+/// Must run after `require_auth`.
+pub async fn require_admin(req: Request, next: Next) -> Response {
+    let allowed = req.extensions().get::<Grant>().is_some_and(Grant::is_admin);
+    if allowed {
+        return next.run(req).await;
+    }
+
+    warn!("Rejected non-admin request to {}", req.uri().path());
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({
+            "error": "Forbidden",
+            "message": "Admin credentials required"
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    const SECRET: &str = "test-secret";
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(&AuthSettings {
+            jwt_secret: Some(SECRET.to_string()),
+            ..AuthSettings::default()
+        })
+        .unwrap()
+    }
+
+    fn token(secret: &str, claims: serde_json::Value) -> String {
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        jsonwebtoken::encode(&Header::default(), &claims, &key).unwrap()
+    }
+
+    fn expires_in(secs: i64) -> i64 {
+        jsonwebtoken::get_current_timestamp() as i64 + secs
+    }
+
+    fn bearer(token: &str) -> Result<Grant, AuthError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        authenticator().authenticate(&headers, &Uri::from_static("/ws"))
+    }
+
+    fn grant(claims: serde_json::Value) -> Grant {
+        serde_json::from_value(claims).unwrap()
+    }
+
+    #[test]
+    fn valid_token_gives_its_grant() {
+        let grant = bearer(&token(
+            SECRET,
+            json!({ "sub": "partner", "matches": ["m1"], "exp": expires_in(3600) }),
+        ))
+        .unwrap();
+        assert_eq!(grant.name, "partner");
+        assert!(!grant.is_anonymous());
+        assert!(!grant.is_admin());
+        assert!(grant.allows("m1", None));
+        assert!(!grant.allows("m2", None));
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let result = bearer(&token(
+            SECRET,
+            json!({ "sub": "partner", "exp": expires_in(-3600) }),
+        ));
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+    }
+
+    #[test]
+    fn token_signed_with_another_key_is_rejected() {
+        let result = bearer(&token(
+            "other-secret",
+            json!({ "sub": "partner", "exp": expires_in(3600) }),
+        ));
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+    }
+
+    #[test]
+    fn token_with_sub_and_name_prefers_name() {
+        let grant = bearer(&token(
+            SECRET,
+            json!({ "sub": "user-42", "name": "Partner App", "exp": expires_in(3600) }),
+        ))
+        .unwrap();
+        assert_eq!(grant.name, "Partner App");
+    }
+
+    #[test]
+    fn missing_credentials_are_rejected_when_enabled() {
+        let result = authenticator().authenticate(&HeaderMap::new(), &Uri::from_static("/ws"));
+        assert!(matches!(result, Err(AuthError::MissingCredentials)));
+    }
+
+    #[test]
+    fn unrestricted_grant_allows_every_match() {
+        let grant = grant(json!({ "name": "partner" }));
+        assert!(grant.allows("m1", None));
+        assert!(grant.allows("m2", Some("t1")));
+        assert!(!grant.restricts_tournaments());
+    }
+
+    #[test]
+    fn match_scope_allows_listed_matches_only() {
+        let grant = grant(json!({ "name": "partner", "matches": ["m1"] }));
+        assert!(grant.allows("m1", None));
+        assert!(grant.allows("m1", Some("t1")));
+        assert!(!grant.allows("m2", None));
+        assert!(!grant.allows("m2", Some("t1")));
+    }
+
+    #[test]
+    fn tournament_scope_needs_a_known_tournament() {
+        let grant = grant(json!({ "name": "partner", "tournaments": ["t1"] }));
+        assert!(grant.restricts_tournaments());
+        assert!(grant.allows("m1", Some("t1")));
+        assert!(!grant.allows("m1", Some("t2")));
+        assert!(!grant.allows("m1", None));
+    }
+
+    #[test]
+    fn match_and_tournament_scopes_combine() {
+        let grant = grant(json!({
+            "name": "partner",
+            "matches": ["m1"],
+            "tournaments": ["t1"],
+        }));
+        assert!(grant.allows("m1", Some("t2")));
+        assert!(grant.allows("m2", Some("t1")));
+        assert!(!grant.allows("m2", Some("t2")));
+    }
+
+    #[test]
+    fn admin_needs_credentials() {
+        assert!(grant(json!({ "name": "ops", "admin": true })).is_admin());
+        assert!(!Grant::anonymous().is_admin());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::pubsub::{apply_update, PendingUpdate};
+use crate::streams::{EntryId, StreamAcker};
+use crate::websocket::WsState;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// Collapses bursts of updates for the same match into one broadcast
+///
+/// The first update for a match opens a window; anything arriving before
+/// it closes is merged in, and the merged update is broadcast when the
+/// window ends. Updates after that open a new window, so the final state
+/// of a burst is always delivered.
+///
+/// Each match with updates in flight has one worker task, which applies
+/// windows one after another: applying may wait on Redis, and a later
+/// window overtaking an earlier one would deliver older state last.
+///
+/// Stream entries are acknowledged only after the update carrying them has
+/// been applied, so entries still in a window when the process dies are
+/// replayed rather than lost.
+#[derive(Clone)]
+pub struct Coalescer {
+    ws_state: WsState,
+    window: Duration,
+    /// Set when updates come from Redis Streams
+    acker: Option<StreamAcker>,
+    inner: Arc<Mutex<Pending>>,
+}
+
+#[derive(Default)]
+struct Pending {
+    /// match_id -> update waiting for its window to close
+    updates: HashMap<String, PendingUpdate>,
+    /// Matches with a worker running
+    workers: HashSet<String>,
+}
+
+impl Coalescer {
+    pub fn new(ws_state: WsState, window: Duration, acker: Option<StreamAcker>) -> Self {
+        Self {
+            ws_state,
+            window,
+            acker,
+            inner: Arc::new(Mutex::new(Pending::default())),
+        }
+    }
+
+    /// Queue an update for broadcast
+    pub async fn submit(&self, match_id: &str, update: PendingUpdate) {
+        // A zero window disables coalescing
+        if self.window.is_zero() {
+            self.apply(match_id, update).await;
+            return;
+        }
+
+        let mut inner = self.inner.lock().await;
+        if let Some(existing) = inner.updates.get_mut(match_id) {
+            existing.merge(update);
+            debug!("Coalesced update for match: {}", match_id);
+            return;
+        }
+        inner.updates.insert(match_id.to_string(), update);
+        // A running worker picks it up once its current apply is done
+        if !inner.workers.insert(match_id.to_string()) {
+            return;
+        }
+        drop(inner);
+
+        tokio::spawn(self.clone().run_worker(match_id.to_string()));
+    }
+
+    /// Apply windows for a match in order until no update is waiting
+    async fn run_worker(self, match_id: String) {
+        loop {
+            tokio::time::sleep(self.window).await;
+            let update = {
+                let mut inner = self.inner.lock().await;
+                match inner.updates.remove(&match_id) {
+                    Some(update) => update,
+                    None => {
+                        inner.workers.remove(&match_id);
+                        return;
+                    }
+                }
+            };
+            self.apply(&match_id, update).await;
+        }
+    }
+
+    /// Broadcast an update, then acknowledge the stream entries it came from
+    async fn apply(&self, match_id: &str, mut update: PendingUpdate) {
+        let acks = std::mem::take(&mut update.acks);
+        apply_update(&self.ws_state, match_id, update).await;
+        self.acknowledge(acks).await;
+    }
+
+    /// Acknowledge stream entries that are done with
+    pub async fn acknowledge(&self, acks: Vec<EntryId>) {
+        if let Some(acker) = &self.acker {
+            acker.ack(acks).await;
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// Browser origins allowed to call the API and open WebSockets
+#[derive(Debug, Clone)]
+pub enum AllowedOrigins {
+    Any,
+    /// Lowercased `scheme://host[:port]` values
+    List(Arc<HashSet<String>>),
+}
+
+impl AllowedOrigins {
+    /// Parse `*` or a list of origins
+    pub fn parse(values: &[String]) -> Result<Self> {
+        if is_any(values) {
+            return Ok(Self::Any);
+        }
+
+        let mut origins = HashSet::new();
+        for origin in values.iter().map(|origin| origin.trim()) {
+            if !origin.starts_with("http://") && !origin.starts_with("https://") {
+                anyhow::bail!("Invalid origin {origin:?}: expected scheme://host[:port]");
+            }
+            origins.insert(origin.trim_end_matches('/').to_ascii_lowercase());
+        }
+        if origins.is_empty() {
+            anyhow::bail!("No origins given; use * to allow any");
+        }
+        Ok(Self::List(Arc::new(origins)))
+    }
+
+    /// Whether a request's `Origin` header is allowed
+    pub fn allows(&self, origin: &HeaderValue) -> bool {
+        match self {
+            Self::Any => true,
+            Self::List(origins) => origin
+                .to_str()
+                .is_ok_and(|origin| origins.contains(&origin.to_ascii_lowercase())),
+        }
+    }
+}
+
+/// Build the CORS layer for the REST and SSE routes
+pub fn cors_layer(
+    origins: &AllowedOrigins,
+    methods: &[String],
+    headers: &[String],
+) -> Result<CorsLayer> {
+    let layer = match origins {
+        AllowedOrigins::Any => CorsLayer::new().allow_origin(Any),
+        AllowedOrigins::List(_) => {
+            let origins = origins.clone();
+            CorsLayer::new().allow_origin(AllowOrigin::predicate(move |origin, _| {
+                origins.allows(origin)
+            }))
+        }
+    };
+
+    let layer = if is_any(methods) {
+        layer.allow_methods(Any)
+    } else {
+        let methods = methods
+            .iter()
+            .map(|method| {
+                method
+                    .to_ascii_uppercase()
+                    .parse::<Method>()
+                    .with_context(|| format!("Invalid method {method:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        layer.allow_methods(methods)
+    };
+
+    let layer = if is_any(headers) {
+        layer.allow_headers(Any)
+    } else {
+        let headers = headers
+            .iter()
+            .map(|header| {
+                header
+                    .parse::<HeaderName>()
+                    .with_context(|| format!("Invalid header name {header:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        layer.allow_headers(headers)
+    };
+
+    Ok(layer)
+}
+
+/// Whether a list is just `*`
+fn is_any(values: &[String]) -> bool {
+    matches!(values, [only] if only.trim() == "*")
+}
@@ -0,0 +1,113 @@
+use crate::models::{ClientMessage, ServerMessage};
+use anyhow::{Context, Result};
+use axum::extract::ws::Message;
+use std::sync::OnceLock;
+
+/// Wire encoding for WebSocket frames, chosen per connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Text frames (the default)
+    #[default]
+    Json,
+    /// Binary MessagePack frames
+    MessagePack,
+    /// Binary CBOR frames
+    Cbor,
+}
+
+impl Encoding {
+    /// Subprotocol names accepted in Sec-WebSocket-Protocol, in preference order
+    pub const SUBPROTOCOLS: [&'static str; 3] = ["msgpack", "cbor", "json"];
+
+    /// Parse an encoding name from a query param or subprotocol
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "msgpack" | "messagepack" => Some(Self::MessagePack),
+            "cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Name as accepted by `from_name`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+            Self::Cbor => "cbor",
+        }
+    }
+
+    /// Serialize a server message into a frame
+    pub fn encode(&self, message: &ServerMessage) -> Result<Message> {
+        Ok(match self {
+            Self::Json => Message::Text(serde_json::to_string(message)?),
+            // Named fields keep the frames self-describing like the JSON ones
+            Self::MessagePack => Message::Binary(
+                rmp_serde::to_vec_named(message).context("Failed to encode MessagePack")?,
+            ),
+            Self::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(message, &mut buf).context("Failed to encode CBOR")?;
+                Message::Binary(buf)
+            }
+        })
+    }
+
+    /// Parse a client frame; text frames are always JSON
+    pub fn decode(&self, frame: &Message) -> Result<ClientMessage> {
+        match frame {
+            Message::Text(text) => Ok(serde_json::from_str(text)?),
+            Message::Binary(data) => match self {
+                Self::Json => Ok(serde_json::from_slice(data)?),
+                Self::MessagePack => {
+                    rmp_serde::from_slice(data).context("Invalid MessagePack message")
+                }
+                Self::Cbor => {
+                    ciborium::from_reader(data.as_slice()).context("Invalid CBOR message")
+                }
+            },
+            _ => anyhow::bail!("Unexpected frame type"),
+        }
+    }
+}
+
+/// Encoded forms of one broadcast message, filled in on first use
+///
+/// Shared by every connection receiving the message, so each encoding is
+/// serialized once per broadcast rather than once per subscriber. Frames
+/// are still copied per connection, since `Message` owns its buffer.
+#[derive(Debug, Default)]
+pub struct FrameCache {
+    json: OnceLock<String>,
+    msgpack: OnceLock<Vec<u8>>,
+    cbor: OnceLock<Vec<u8>>,
+}
+
+impl FrameCache {
+    /// JSON text of the message
+    pub fn json(&self, message: &ServerMessage) -> Result<&str> {
+        if let Some(json) = self.json.get() {
+            return Ok(json);
+        }
+        let json = serde_json::to_string(message)?;
+        Ok(self.json.get_or_init(|| json))
+    }
+
+    /// Frame for a connection using `encoding`
+    pub fn frame(&self, encoding: Encoding, message: &ServerMessage) -> Result<Message> {
+        let cache = match encoding {
+            Encoding::Json => return Ok(Message::Text(self.json(message)?.to_owned())),
+            Encoding::MessagePack => &self.msgpack,
+            Encoding::Cbor => &self.cbor,
+        };
+
+        if let Some(bytes) = cache.get() {
+            return Ok(Message::Binary(bytes.clone()));
+        }
+        let Message::Binary(bytes) = encoding.encode(message)? else {
+            anyhow::bail!("Binary encoding produced a text frame");
+        };
+        Ok(Message::Binary(cache.get_or_init(|| bytes).clone()))
+    }
+}
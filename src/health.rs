@@ -0,0 +1,148 @@
+use crate::redis_client::RedisClient;
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+/// Longest the readiness probe waits for Redis to answer
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What the update listener is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListenerState {
+    /// Not connected yet
+    Starting,
+    /// Subscribed and receiving updates
+    Connected,
+    /// Dropped; waiting to reconnect
+    Reconnecting,
+}
+
+/// Update listener state shared with the readiness probe
+#[derive(Clone)]
+pub struct ListenerStatus {
+    /// `pubsub` or `streams`
+    source: &'static str,
+    inner: Arc<Mutex<ListenerInner>>,
+}
+
+struct ListenerInner {
+    state: ListenerState,
+    /// When `state` last changed
+    since: Instant,
+    last_message: Option<Instant>,
+}
+
+impl ListenerStatus {
+    pub fn new(source: &'static str) -> Self {
+        Self {
+            source,
+            inner: Arc::new(Mutex::new(ListenerInner {
+                state: ListenerState::Starting,
+                since: Instant::now(),
+                last_message: None,
+            })),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ListenerInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_state(&self, state: ListenerState) {
+        let mut inner = self.lock();
+        if inner.state != state {
+            inner.state = state;
+            inner.since = Instant::now();
+        }
+    }
+
+    /// Note that an update arrived
+    pub fn record_message(&self) {
+        self.lock().last_message = Some(Instant::now());
+    }
+}
+
+/// State for the readiness probe
+#[derive(Clone)]
+pub struct ReadyState {
+    pub redis: RedisClient,
+    pub listener: ListenerStatus,
+    /// Not ready after this long without an update, if set
+    pub max_update_age: Option<Duration>,
+}
+
+/// Handler for GET /ready
+///
+/// Unlike `/health`, fails with 503 when Redis doesn't answer, the update
+/// listener isn't connected, or (if configured) updates have stopped.
+pub async fn ready(State(state): State<ReadyState>) -> (StatusCode, Json<serde_json::Value>) {
+    let started = Instant::now();
+    let redis = match tokio::time::timeout(PING_TIMEOUT, state.redis.ping()).await {
+        Ok(Ok(())) => Ok(started.elapsed()),
+        // The response is public; keep Redis' own error in the logs
+        Ok(Err(e)) => {
+            error!("Readiness check failed to ping Redis: {}", e);
+            Err("redis unavailable".to_string())
+        }
+        Err(_) => Err(format!("No reply within {PING_TIMEOUT:?}")),
+    };
+
+    let (listener_state, since, last_message) = {
+        let inner = state.listener.lock();
+        (inner.state, inner.since, inner.last_message)
+    };
+    let message_age = last_message.map(|at| at.elapsed());
+    // Before the first update, count from when the listener connected
+    let stale = state.max_update_age.is_some_and(|max| {
+        listener_state == ListenerState::Connected
+            && message_age.unwrap_or_else(|| since.elapsed()) > max
+    });
+
+    let ready = redis.is_ok() && listener_state == ListenerState::Connected && !stale;
+    if !ready {
+        warn!(
+            "Not ready: redis {:?}, listener {:?}, stale {}",
+            redis, listener_state, stale
+        );
+    }
+
+    let listener_check = match (listener_state, stale) {
+        (ListenerState::Connected, false) => "ok",
+        (ListenerState::Connected, true) => "stale",
+        _ => "error",
+    };
+    let redis_check = match &redis {
+        Ok(latency) => json!({
+            "status": "ok",
+            "latency_ms": latency.as_secs_f64() * 1000.0
+        }),
+        Err(e) => json!({ "status": "error", "error": e }),
+    };
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(json!({
+            "status": if ready { "ok" } else { "degraded" },
+            "service": "cricket-live-score-backend",
+            "checks": {
+                "redis": redis_check,
+                "listener": {
+                    "status": listener_check,
+                    "source": state.listener.source,
+                    "state": listener_state,
+                    "state_age_secs": since.elapsed().as_secs(),
+                    "last_message_age_secs": message_age.map(|age| age.as_secs())
+                }
+            }
+        })),
+    )
+}
@@ -0,0 +1,156 @@
+use crate::auth::Grant;
+use crate::models::{LiveScore, ServerMessage};
+use crate::websocket::{MatchEvent, WsState};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{debug, error};
+
+/// Longest a poll may wait; the request timeout must be longer
+pub const MAX_POLL_SECS: u64 = 25;
+
+/// Query parameters for the long-poll endpoint
+#[derive(Debug, Deserialize)]
+pub struct PollParams {
+    /// Version from the previous response
+    pub since: Option<String>,
+    /// Seconds to wait for an update (capped at 25)
+    pub timeout: Option<u64>,
+}
+
+/// Handler for GET /api/matches/:match_id/poll
+///
+/// Returns the live score as soon as it is newer than `since`, waiting up
+/// to `timeout` seconds for the next update. Responds 204 if nothing
+/// changed in time; the client should poll again with the same `since`.
+pub async fn poll_score(
+    Path(match_id): Path<String>,
+    Extension(grant): Extension<Grant>,
+    Query(params): Query<PollParams>,
+    State(state): State<WsState>,
+) -> Response {
+    let since = params
+        .since
+        .as_deref()
+        .and_then(|version| state.parse_event_id(version));
+
+    let channel = match state.open_match_channel(&grant, &match_id).await {
+        Ok(channel) => channel,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let (mut rx, latest_seq, missed) = match since {
+        Some(seq) => channel.subscribe_after(seq),
+        None => {
+            let (rx, latest_seq) = channel.subscribe();
+            (rx, latest_seq, None)
+        }
+    };
+
+    // No usable version, or the client missed an update: answer straight away
+    match missed {
+        None => return current_score(&state, &match_id, latest_seq).await,
+        Some(events) => {
+            if let Some((seq, score)) = events.into_iter().rev().find_map(score_from_event) {
+                return score_response(&state, seq, score);
+            }
+        }
+    }
+
+    let wait = Duration::from_secs(
+        params
+            .timeout
+            .unwrap_or(MAX_POLL_SECS)
+            .clamp(1, MAX_POLL_SECS),
+    );
+
+    let result = tokio::time::timeout(wait, async {
+        loop {
+            let received = tokio::select! {
+                received = rx.recv() => received,
+                _ = state.shutting_down() => return Some(shutting_down(&state)),
+            };
+            match received {
+                Ok(event) => {
+                    if let Some((seq, score)) = score_from_event(event) {
+                        return Some(score_response(&state, seq, score));
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let latest_seq = channel.latest_seq();
+                    return Some(current_score(&state, &match_id, latest_seq).await);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Some(response)) => response,
+        _ => {
+            debug!("Long poll timed out for match: {}", match_id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+    }
+}
+
+/// Tell the client to poll again elsewhere, after a spread-out delay
+fn shutting_down(state: &WsState) -> Response {
+    let reconnect_after = state.reconnect_hint();
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(json!({
+            "error": "Server shutting down",
+            "reconnect_after_ms": reconnect_after.as_millis() as u64
+        })),
+    )
+        .into_response();
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(reconnect_after.as_secs_f64().ceil() as u64),
+    );
+    response
+}
+
+/// Read the score from Redis and label it with the given version
+async fn current_score(state: &WsState, match_id: &str, seq: u64) -> Response {
+    match state.redis.get_live_score(match_id).await {
+        Ok(score) => score_response(state, seq, score),
+        Err(e) => {
+            error!("Failed to get live score: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Internal server error",
+                    "message": "Failed to fetch live score"
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Pull the live score out of an event, if it carries one
+fn score_from_event(event: MatchEvent) -> Option<(u64, LiveScore)> {
+    match &*event.message {
+        ServerMessage::ScoreUpdate { data } => Some((event.seq, (**data).clone())),
+        ServerMessage::FullState { data } => Some((event.seq, data.score.clone())),
+        _ => None,
+    }
+}
+
+/// Build a poll response carrying a score and its version
+fn score_response(state: &WsState, seq: u64, score: LiveScore) -> Response {
+    Json(json!({
+        "version": state.event_id(seq),
+        "data": score
+    }))
+    .into_response()
+}
@@ -1,22 +1,51 @@
+mod admin;
 mod api;
+mod auth;
+mod coalesce;
+mod config;
+mod cors;
+mod encoding;
+mod health;
+mod longpoll;
+mod metrics;
 mod models;
+mod outbox;
+mod presence;
 mod pubsub;
+mod ratelimit;
 mod redis_client;
+mod sse;
+mod streams;
 mod websocket;
 
 use anyhow::{Context, Result};
-use axum::{routing::get, Router};
+use auth::Authenticator;
+use axum::{
+    middleware,
+    routing::{get, post},
+    Extension, Router,
+};
+use coalesce::Coalescer;
+use config::{Config, IngestMode, RedisConfig};
+use cors::AllowedOrigins;
+use health::{ListenerState, ListenerStatus, ReadyState};
+use presence::Presence;
+use pubsub::UpdateFeed;
+use ratelimit::{HttpRateLimit, RateLimiter, TrustedProxies};
 use redis_client::RedisClient;
+use std::future::IntoFuture;
 use std::net::SocketAddr;
 use std::panic;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
+use streams::StreamAcker;
 use tower::ServiceBuilder;
-use tower_http::cors::{Any, CorsLayer};
 use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
 use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
-use websocket::WsState;
+use websocket::{WsConfig, WsState};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -50,6 +79,28 @@ async fn main() -> Result<()> {
         );
     }));
 
+    // Command line: --config PATH (or CONFIG_FILE), --print-config
+    let mut config_path = std::env::var_os("CONFIG_FILE").map(PathBuf::from);
+    let mut print_config = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                config_path = Some(args.next().context("--config needs a path")?.into());
+            }
+            "--print-config" => print_config = true,
+            other => anyhow::bail!(
+                "Unknown argument: {other} (expected --config PATH or --print-config)"
+            ),
+        }
+    }
+
+    let config = Config::load(config_path.as_deref())?;
+    if print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
     // Initialize tracing with environment-based log level
     let log_level = std::env::var("RUST_LOG")
         .unwrap_or_else(|_| "info".to_string())
@@ -60,74 +111,251 @@ async fn main() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber)?;
 
     info!("Starting Cricket Live Score Backend (Rust)");
+    if let Some(path) = &config_path {
+        info!("Loaded configuration from {}", path.display());
+    }
+
+    // Optional JWT / API key authentication
+    let authenticator = Authenticator::new(&config.auth.settings())?;
+    if authenticator.is_enabled() {
+        info!("Authentication enabled");
+    } else {
+        warn!("Authentication disabled; all routes are open");
+    }
 
-    // Get configuration from environment
-    let redis_url =
-        std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
-    let host = std::env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
-    let port = std::env::var("PORT")
-        .unwrap_or_else(|_| "3001".to_string())
-        .parse::<u16>()
-        .context("Invalid PORT")?;
+    // Browser origins allowed for CORS and WebSocket upgrades
+    let allowed_origins = AllowedOrigins::parse(&config.cors.allowed_origins)
+        .context("Invalid CORS_ALLOWED_ORIGINS")?;
+    if matches!(allowed_origins, AllowedOrigins::Any) {
+        warn!("CORS_ALLOWED_ORIGINS is *; any website can use this API");
+    }
+    let cors = cors::cors_layer(
+        &allowed_origins,
+        &config.cors.allowed_methods,
+        &config.cors.allowed_headers,
+    )
+    .context("Invalid CORS settings")?;
+
+    // Token bucket rate limits per client (0 disables)
+    let trusted_proxies = TrustedProxies::parse(&config.rate_limit.trusted_proxies)
+        .context("Invalid TRUSTED_PROXIES")?;
+    let http_rate_limit = HttpRateLimit {
+        limiter: RateLimiter::new(config.rate_limit.http_per_sec, config.rate_limit.http_burst),
+        proxies: trusted_proxies,
+    };
 
     // Create Redis client with retry logic
-    let redis_client = connect_to_redis_with_retry(&redis_url, 10, 5).await?;
+    let redis_client = connect_to_redis_with_retry(&config.redis).await?;
 
     info!("Connected to Redis");
 
     // Create WebSocket state
-    let ws_state = WsState::new(redis_client.clone());
+    let ws = &config.websocket;
+    let ws_state = WsState::new(
+        redis_client.clone(),
+        WsConfig {
+            ping_interval: Duration::from_secs(ws.ping_interval_secs),
+            idle_timeout: Duration::from_secs(ws.idle_timeout_secs),
+            send_queue_size: ws.send_queue_size,
+            slow_client_policy: ws.slow_client_policy(),
+            max_connections_per_ip: ws.max_connections_per_ip,
+            max_subscriptions: ws.max_subscriptions,
+            allowed_origins,
+            message_rate: config.rate_limit.ws_per_sec,
+            message_burst: config.rate_limit.ws_burst,
+            broadcast_capacity: ws.broadcast_capacity,
+            reconnect_spread: Duration::from_secs(config.server.shutdown_reconnect_spread_secs),
+        },
+    );
+
+    // Free channels for matches nobody is watching any more
+    let prune_task = tokio::spawn(ws_state.clone().prune_channels());
+
+    // Share viewer counts with other nodes through Redis
+    let presence = Arc::new(Presence::new(
+        config.cluster.node_id(ws_state.boot_id),
+        ws_state.clone(),
+        Duration::from_secs(config.cluster.presence_interval_secs),
+        Duration::from_secs(config.cluster.presence_ttl_secs),
+    ));
+    info!("Cluster node ID: {}", presence.node_id());
+    let presence_task = tokio::spawn(presence.clone().run());
+
+    // Periodic viewer counts for match subscribers
+    let viewers_task = (config.websocket.viewers_interval_secs > 0).then(|| {
+        tokio::spawn(
+            presence
+                .clone()
+                .push_viewers(Duration::from_secs(config.websocket.viewers_interval_secs)),
+        )
+    });
 
-    // Start Redis Pub/Sub listener in background with auto-reconnect
-    let ws_state_clone = ws_state.clone();
-    let redis_url_clone = redis_url.clone();
-    tokio::spawn(async move {
+    // Update ingestion: Pub/Sub or Redis Streams
+    let stream_settings =
+        (config.ingest.mode == IngestMode::Streams).then(|| config.ingest.stream.settings());
+    let reconnect_delay = Duration::from_secs(config.ingest.reconnect_delay_secs);
+    let update_feed = match &stream_settings {
+        Some(settings) => UpdateFeed::Streams(settings.layout.clone()),
+        None => UpdateFeed::PubSub,
+    };
+
+    // Start update listener in background with auto-reconnect
+    let coalescer = Coalescer::new(
+        ws_state.clone(),
+        Duration::from_millis(config.ingest.coalesce_window_ms),
+        stream_settings
+            .as_ref()
+            .map(|settings| StreamAcker::new(redis_client.clone(), settings.group.clone())),
+    );
+    let redis_url_clone = config.redis.url.clone();
+    let listener_status = ListenerStatus::new(match config.ingest.mode {
+        IngestMode::Pubsub => "pubsub",
+        IngestMode::Streams => "streams",
+    });
+    let status = listener_status.clone();
+    let update_listener = tokio::spawn(async move {
         loop {
-            warn!("Starting Redis Pub/Sub listener...");
-            match pubsub::start_pubsub_listener(&redis_url_clone, ws_state_clone.clone()).await {
+            let result = match &stream_settings {
+                Some(settings) => {
+                    warn!("Starting Redis Streams listener...");
+                    streams::start_stream_listener(
+                        &redis_url_clone,
+                        coalescer.clone(),
+                        settings,
+                        &status,
+                    )
+                    .await
+                }
+                None => {
+                    warn!("Starting Redis Pub/Sub listener...");
+                    pubsub::start_pubsub_listener(&redis_url_clone, coalescer.clone(), &status)
+                        .await
+                }
+            };
+
+            match result {
                 Ok(_) => {
-                    warn!("Pub/Sub listener ended normally");
+                    warn!("Update listener ended normally");
                 }
                 Err(e) => {
-                    error!("Pub/Sub listener error: {}. Reconnecting in 5s...", e);
+                    error!(
+                        "Update listener error: {}. Reconnecting in {:?}...",
+                        e, reconnect_delay
+                    );
                 }
             }
 
             // Wait before reconnecting
-            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            warn!("Attempting to reconnect Pub/Sub listener...");
+            status.set_state(ListenerState::Reconnecting);
+            tokio::time::sleep(reconnect_delay).await;
+            warn!("Attempting to reconnect update listener...");
         }
     });
 
-    // Configure CORS
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-
     // Build router with nested routers for different states
+    // (the last route_layer runs first: authenticate, then rate limit)
+    // Liveness stays unconditional; readiness checks dependencies
+    let health_routes = Router::new()
+        .route("/health", get(api::health_check))
+        .route("/ready", get(health::ready))
+        .with_state(ReadyState {
+            redis: redis_client.clone(),
+            listener: listener_status,
+            max_update_age: (config.ingest.max_update_age_secs > 0)
+                .then(|| Duration::from_secs(config.ingest.max_update_age_secs)),
+        });
+
     let api_routes = Router::new()
         .route("/api/matches/live", get(api::get_live_matches))
+        .route_layer(middleware::from_fn_with_state(
+            http_rate_limit.clone(),
+            ratelimit::rate_limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            authenticator.clone(),
+            auth::require_auth,
+        ))
         .with_state(redis_client);
 
     let ws_routes = Router::new()
         .route("/ws", get(websocket::ws_handler))
-        .with_state(ws_state);
+        .route("/api/matches/:match_id/events", get(sse::match_events))
+        .route("/api/matches/:match_id/poll", get(longpoll::poll_score))
+        .route_layer(middleware::from_fn_with_state(
+            http_rate_limit.clone(),
+            ratelimit::rate_limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            authenticator.clone(),
+            auth::require_auth,
+        ))
+        .with_state(ws_state.clone());
+
+    // Only for credentials marked admin: authenticate, check admin, rate limit
+    let admin_routes = Router::new()
+        .route("/admin/matches", get(admin::list_matches))
+        .route(
+            "/admin/matches/:match_id/rebroadcast",
+            post(admin::rebroadcast_match),
+        )
+        .route("/admin/nodes", get(admin::list_nodes))
+        .route("/admin/connections", get(admin::list_connections))
+        .route(
+            "/admin/connections/:id",
+            get(admin::get_connection).delete(admin::close_connection),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            http_rate_limit,
+            ratelimit::rate_limit,
+        ))
+        .route_layer(middleware::from_fn(auth::require_admin))
+        .layer(Extension(update_feed))
+        .route_layer(middleware::from_fn_with_state(
+            authenticator,
+            auth::require_auth,
+        ))
+        .with_state(ws_state.clone());
+
+    // Scraped by Prometheus, so left outside authentication and rate limits
+    let metrics_routes = Router::new()
+        .route("/metrics", get(metrics::metrics_handler))
+        .with_state(ws_state.clone());
 
     // Build middleware stack for resilience
     let middleware = ServiceBuilder::new()
         .layer(TraceLayer::new_for_http())
-        .layer(TimeoutLayer::new(Duration::from_secs(30))) // 30s timeout for all requests
+        .layer(middleware::from_fn(metrics::track_http))
+        .layer(TimeoutLayer::new(Duration::from_secs(
+            config.server.request_timeout_secs,
+        )))
         .layer(cors);
 
     let app = Router::new()
-        .route("/health", get(api::health_check))
+        .merge(health_routes)
         .merge(api_routes)
         .merge(ws_routes)
+        .merge(admin_routes)
         .layer(middleware);
 
+    // Metrics skip auth, so they get their own listener off the public port
+    if config.server.metrics_port > 0 {
+        let metrics_addr = SocketAddr::new(
+            config.server.metrics_host.parse()?,
+            config.server.metrics_port,
+        );
+        let metrics_listener = tokio::net::TcpListener::bind(metrics_addr)
+            .await
+            .context("Failed to bind metrics address")?;
+        info!("Metrics on http://{}/metrics", metrics_addr);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(metrics_listener, metrics_routes).await {
+                error!("Metrics server error: {}", e);
+            }
+        });
+    }
+
     // Start server
-    let addr = SocketAddr::new(host.parse()?, port);
+    let addr = SocketAddr::new(config.server.host.parse()?, config.server.port);
     info!("Server listening on http://{}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr)
@@ -137,36 +365,81 @@ async fn main() -> Result<()> {
     // Setup graceful shutdown
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
 
-    // Spawn signal handler
-    tokio::spawn(async move {
-        shutdown_signal().await;
-        info!("Shutdown signal received, starting graceful shutdown...");
-        let _ = shutdown_tx.send(());
-    });
-
     // Run server with graceful shutdown
     info!("Server ready to accept connections");
-    axum::serve(listener, app)
+    // Connection info gives handlers the client address for per-IP limits
+    let mut server = tokio::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
         .with_graceful_shutdown(async {
             shutdown_rx.await.ok();
         })
-        .await
-        .context("Server error")?;
+        .into_future(),
+    );
+
+    tokio::select! {
+        result = &mut server => {
+            result.context("Server task failed")?.context("Server error")?;
+            anyhow::bail!("Server stopped unexpectedly");
+        }
+        _ = shutdown_signal() => {}
+    }
+
+    // Stop taking updates, tell clients to go elsewhere, then wait for
+    // them to leave (hints are spread out so they don't all reconnect at once)
+    let drain = Duration::from_secs(config.server.shutdown_drain_secs);
+    info!(
+        "Shutdown signal received, draining connections for up to {:?}...",
+        drain
+    );
+    update_listener.abort();
+    presence_task.abort();
+    prune_task.abort();
+    if let Some(task) = viewers_task {
+        task.abort();
+    }
+    if let Err(e) = presence.leave().await {
+        warn!("Failed to deregister node: {}", e);
+    }
+    ws_state.begin_shutdown();
+    let _ = shutdown_tx.send(());
+
+    let drained = tokio::time::timeout(drain, async {
+        let result = (&mut server).await;
+        ws_state.drained().await;
+        result
+    })
+    .await;
+    match drained {
+        Ok(result) => result
+            .context("Server task failed")?
+            .context("Server error")?,
+        Err(_) => warn!(
+            "Drain period ended with {} WebSocket connections still open",
+            ws_state.connection_count().await
+        ),
+    }
 
     info!("Server shutdown complete");
     Ok(())
 }
 
 /// Connect to Redis with retry logic
-async fn connect_to_redis_with_retry(
-    redis_url: &str,
-    max_retries: u32,
-    retry_delay_secs: u64,
-) -> Result<RedisClient> {
-    info!("Connecting to Redis at: {}", redis_url);
+async fn connect_to_redis_with_retry(config: &RedisConfig) -> Result<RedisClient> {
+    info!("Connecting to Redis at: {}", config.redacted_url());
 
+    let max_retries = config.connect_retries;
+    let retry_delay = Duration::from_secs(config.connect_retry_delay_secs);
     for attempt in 1..=max_retries {
-        match RedisClient::new(redis_url).await {
+        match RedisClient::new(
+            &config.url,
+            config.command_retries,
+            Duration::from_millis(config.command_retry_delay_ms),
+        )
+        .await
+        {
             Ok(client) => {
                 info!("Successfully connected to Redis on attempt {}", attempt);
                 return Ok(client);
@@ -174,16 +447,16 @@ async fn connect_to_redis_with_retry(
             Err(e) => {
                 if attempt < max_retries {
                     warn!(
-                        "Failed to connect to Redis (attempt {}/{}): {}. Retrying in {}s...",
-                        attempt, max_retries, e, retry_delay_secs
+                        "Failed to connect to Redis (attempt {}/{}): {}. Retrying in {:?}...",
+                        attempt, max_retries, e, retry_delay
                     );
-                    tokio::time::sleep(tokio::time::Duration::from_secs(retry_delay_secs)).await;
+                    tokio::time::sleep(retry_delay).await;
                 } else {
                     error!(
                         "Failed to connect to Redis after {} attempts: {}",
                         max_retries, e
                     );
-                    return Err(e);
+                    return Err(e.into());
                 }
             }
         }
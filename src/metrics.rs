@@ -0,0 +1,177 @@
+use crate::websocket::WsState;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::sync::LazyLock;
+use std::time::Instant;
+use tracing::error;
+
+/// Open WebSocket connections
+pub static WS_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("ws_connections_active", "Open WebSocket connections")
+        .expect("register ws_connections_active")
+});
+
+/// Receivers per match channel, refreshed on scrape
+static MATCH_SUBSCRIBERS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "match_subscribers",
+        "WebSocket, SSE and long-poll subscribers per match",
+        &["match_id"]
+    )
+    .expect("register match_subscribers")
+});
+
+/// Broadcast deliveries, one per receiver of each message
+pub static BROADCAST_SENT: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "broadcast_messages_sent_total",
+        "Match broadcasts handed to subscribers"
+    )
+    .expect("register broadcast_messages_sent_total")
+});
+
+/// Time from a broadcast being sent on a match channel to it being
+/// written to a WebSocket
+pub static BROADCAST_DELIVERY: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "broadcast_delivery_seconds",
+        "Fan-out latency from match broadcast to WebSocket write",
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]
+    )
+    .expect("register broadcast_delivery_seconds")
+});
+
+/// Broadcasts a subscriber never got: `lagged` behind the channel, or
+/// dropped from a `slow_client`'s send queue
+pub static BROADCAST_DROPPED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "broadcast_messages_dropped_total",
+        "Match broadcasts skipped for a subscriber",
+        &["reason"]
+    )
+    .expect("register broadcast_messages_dropped_total")
+});
+
+/// Updates read from Redis, by `pubsub` or `streams`
+pub static UPDATES_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "updates_received_total",
+        "Match updates received from Redis",
+        &["source"]
+    )
+    .expect("register updates_received_total")
+});
+
+/// Time taken by each `RedisClient` operation, including retries, by
+/// `ok` or `error` outcome
+pub static REDIS_COMMAND_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "redis_command_duration_seconds",
+        "Redis read latency by operation",
+        &["operation", "outcome"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]
+    )
+    .expect("register redis_command_duration_seconds")
+});
+
+/// Failed attempts retried by `with_retry`
+pub static REDIS_RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "redis_command_retries_total",
+        "Redis operations retried after a failure",
+        &["operation"]
+    )
+    .expect("register redis_command_retries_total")
+});
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests by route and status",
+        &["method", "path", "status"]
+    )
+    .expect("register http_requests_total")
+});
+
+static HTTP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latency by route",
+        &["method", "path"]
+    )
+    .expect("register http_request_duration_seconds")
+});
+
+/// Counts an open WebSocket until dropped
+pub struct ConnectionGauge;
+
+impl ConnectionGauge {
+    pub fn new() -> Self {
+        WS_CONNECTIONS.inc();
+        Self
+    }
+}
+
+impl Drop for ConnectionGauge {
+    fn drop(&mut self) {
+        WS_CONNECTIONS.dec();
+    }
+}
+
+/// Middleware recording request counts and latency per route
+///
+/// Labels use the route pattern (`/api/matches/:match_id/poll`) so match
+/// IDs don't each get a series. WebSocket and SSE requests are timed up to
+/// the upgrade or first byte, not for the life of the stream.
+pub async fn track_http(req: Request, next: Next) -> Response {
+    let method = req.method().to_string();
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let started = Instant::now();
+
+    let response = next.run(req).await;
+
+    HTTP_DURATION
+        .with_label_values(&[&method, &path])
+        .observe(started.elapsed().as_secs_f64());
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &path, response.status().as_str()])
+        .inc();
+    response
+}
+
+/// Handler for GET /metrics in the Prometheus text format, served on its
+/// own listener
+pub async fn metrics_handler(State(state): State<WsState>) -> Response {
+    MATCH_SUBSCRIBERS.reset();
+    for (match_id, channel) in state.channels.read().await.iter() {
+        MATCH_SUBSCRIBERS
+            .with_label_values(&[match_id])
+            .set(channel.sender.receiver_count() as i64);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut body) {
+        error!("Failed to encode metrics: {}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        body,
+    )
+        .into_response()
+}
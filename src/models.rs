@@ -1,5 +1,14 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current Unix time in milliseconds
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
 
 /// Match information (static data)
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +24,7 @@ pub struct MatchInfo {
     pub toss_decision: Option<String>,
     pub stage: Option<String>,
     pub group_id: Option<String>,
+    pub tournament_id: Option<String>,
 }
 
 /// Live score data (highly dynamic)
@@ -75,7 +85,7 @@ pub struct Scorecard {
 }
 
 /// Match summary for the live matches list
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MatchSummary {
     pub match_id: String,
     pub team_a: String,
@@ -85,6 +95,10 @@ pub struct MatchSummary {
     pub overs: String,
     pub status: String,
     pub stage: Option<String>,
+    pub tournament_id: Option<String>,
+    /// Concurrent viewers across all backend nodes
+    #[serde(default)]
+    pub viewers: u64,
 }
 
 /// Full match state (sent on initial subscription)
@@ -95,16 +109,148 @@ pub struct FullMatchState {
     pub score: LiveScore,
     pub scorecard_inn_1: Option<Scorecard>,
     pub scorecard_inn_2: Option<Scorecard>,
+    /// Concurrent viewers across all backend nodes
+    #[serde(default)]
+    pub viewers: u64,
+}
+
+/// A backend node registered in Redis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeInfo {
+    pub node_id: String,
+    /// Unix time in milliseconds
+    pub started_at: u64,
+    /// Unix time in milliseconds of the last heartbeat
+    pub updated_at: u64,
+    /// Open WebSocket connections
+    pub connections: usize,
+    /// Matches with at least one viewer on this node
+    pub matches: usize,
+    /// Viewers of all matches on this node
+    pub viewers: u64,
+}
+
+/// Current WebSocket protocol version; clients that never send `hello` are on 0
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features negotiated in `hello`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    /// Partial updates instead of whole objects
+    Deltas,
+    /// Per-subscription topic filters
+    Topics,
+    /// A feature from a newer client that this server doesn't know
+    #[serde(other)]
+    Unknown,
+}
+
+impl Feature {
+    /// Features this server implements
+    pub const SUPPORTED: [Feature; 1] = [Feature::Topics];
 }
 
 /// WebSocket message types
+///
+/// `id` is optional and echoed back in the reply so clients can match them up.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "action")]
 pub enum ClientMessage {
+    /// Optional handshake; must be the first message if sent
+    #[serde(rename = "hello")]
+    Hello {
+        #[serde(default)]
+        id: Option<String>,
+        version: u32,
+        #[serde(default)]
+        features: Vec<Feature>,
+    },
     #[serde(rename = "subscribe")]
-    Subscribe { match_id: String },
+    Subscribe {
+        #[serde(default)]
+        id: Option<String>,
+        match_id: String,
+        /// Only receive these kinds of update (empty = everything)
+        #[serde(default)]
+        topics: Vec<Topic>,
+    },
     #[serde(rename = "unsubscribe")]
-    Unsubscribe { match_id: String },
+    Unsubscribe {
+        #[serde(default)]
+        id: Option<String>,
+        match_id: String,
+    },
+    #[serde(rename = "subscribe_list")]
+    SubscribeList {
+        #[serde(default)]
+        id: Option<String>,
+    },
+    #[serde(rename = "unsubscribe_list")]
+    UnsubscribeList {
+        #[serde(default)]
+        id: Option<String>,
+    },
+}
+
+impl ClientMessage {
+    /// Client-supplied ID to echo in the reply
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            ClientMessage::Hello { id, .. }
+            | ClientMessage::Subscribe { id, .. }
+            | ClientMessage::Unsubscribe { id, .. }
+            | ClientMessage::SubscribeList { id }
+            | ClientMessage::UnsubscribeList { id } => id.as_deref(),
+        }
+    }
+}
+
+/// Kinds of per-match update a client can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// `score_update`
+    Score,
+    /// `scorecard_update`
+    Scorecard,
+    /// `ball_event` with commentary text
+    Commentary,
+    /// Every `ball_event`
+    BallEvents,
+    /// `ball_event` for wickets only
+    Wickets,
+    /// `viewers` counts
+    Viewers,
+}
+
+/// How a match changed in the live matches list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListChange {
+    Started,
+    Updated,
+    Ended,
+}
+
+/// Machine-readable reason carried by an `error` message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// No match with that ID
+    MatchNotFound,
+    /// The message couldn't be parsed
+    InvalidMessage,
+    /// Too many requests; slow down
+    RateLimited,
+    /// Missing or insufficient credentials
+    Unauthorized,
+    /// Something failed on the server; details are in its logs
+    Internal,
+    /// Too many WebSocket connections from this IP
+    TooManyConnections,
+    /// Too many matches subscribed on this connection
+    TooManySubscriptions,
 }
 
 /// Server-to-client WebSocket messages
@@ -117,8 +263,186 @@ pub enum ServerMessage {
     ScoreUpdate { data: Box<LiveScore> },
     #[serde(rename = "scorecard_update")]
     ScorecardUpdate { data: Scorecard, inning: u8 },
+    #[serde(rename = "ball_event")]
+    BallEvent { data: Box<BallEvent> },
+    #[serde(rename = "match_list")]
+    MatchList { data: Vec<MatchSummary> },
+    #[serde(rename = "list_update")]
+    ListUpdate {
+        change: ListChange,
+        data: MatchSummary,
+    },
+    /// Reply to `hello` with the version and features to use
+    #[serde(rename = "hello")]
+    Hello {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        version: u32,
+        features: Vec<Feature>,
+    },
+    /// A subscribe succeeded; `match_id` is absent for the live matches list
+    #[serde(rename = "subscribed")]
+    Subscribed {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        match_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        topics: Vec<Topic>,
+    },
+    /// An unsubscribe was processed; `match_id` is absent for the list
+    #[serde(rename = "unsubscribed")]
+    Unsubscribed {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        match_id: Option<String>,
+    },
     #[serde(rename = "error")]
-    Error { message: String },
+    Error {
+        /// `id` of the client message that failed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        code: ErrorCode,
+        message: String,
+        /// Match the failed request was about
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        match_id: Option<String>,
+        /// Server-assigned ID of the failed request, for matching up with logs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    /// Some updates for a match were skipped; newer state follows
+    #[serde(rename = "resync")]
+    Resync { match_id: String, skipped: u64 },
+    /// Sent every ping interval so clients can spot a stale feed
+    #[serde(rename = "heartbeat")]
+    Heartbeat {
+        /// Unix time in milliseconds
+        server_time: u64,
+        /// Seconds until the next heartbeat
+        interval_secs: u64,
+    },
+    /// The server is going away and will close the connection; reconnect
+    /// (to another instance) after waiting this long
+    #[serde(rename = "shutdown")]
+    Shutdown { reconnect_after_ms: u64 },
+    /// How many clients are watching a match, across all nodes when known.
+    /// Sent periodically, only when the count changes
+    #[serde(rename = "viewers")]
+    Viewers { match_id: String, viewers: u64 },
+}
+
+/// A single delivery as pushed by the scorer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BallEvent {
+    pub inning: u8,
+    pub over: String, // "10.4"
+    pub batsman_id: String,
+    pub bowler_id: String,
+    pub runs: u8,
+    #[serde(default)]
+    pub extras: Option<String>, // "wd", "nb", "b", "lb"
+    #[serde(default)]
+    pub is_wicket: bool,
+    #[serde(default)]
+    pub dismissal: Option<String>,
+    #[serde(default)]
+    pub commentary: String,
+}
+
+/// Current version of the match_updates:{match_id} payload
+pub const UPDATE_PAYLOAD_VERSION: u8 = 1;
+
+/// What happened in the match to trigger an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateEvent {
+    Ball,
+    Wicket,
+    InningsBreak,
+    MatchStarted,
+    MatchEnded,
+    Correction,
+    #[serde(other)]
+    Other,
+}
+
+/// Parts of the match state touched by an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UpdateSection {
+    #[serde(rename = "info")]
+    Info,
+    #[serde(rename = "score")]
+    Score,
+    #[serde(rename = "scorecard_1")]
+    Scorecard1,
+    #[serde(rename = "scorecard_2")]
+    Scorecard2,
+}
+
+/// Payload published on match_updates:{match_id}
+///
+/// Sections listed in `sections` but not carried in the payload are
+/// re-read from Redis, so a writer may send only the event and let the
+/// backend fetch the data.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchUpdate {
+    pub v: u8,
+    /// Monotonic per-match sequence number (0 = unsequenced)
+    #[serde(default)]
+    pub seq: u64,
+    pub event: UpdateEvent,
+    #[serde(default)]
+    pub sections: Vec<UpdateSection>,
+    #[serde(default)]
+    pub score: Option<LiveScore>,
+    #[serde(default)]
+    pub scorecard_inn_1: Option<Scorecard>,
+    #[serde(default)]
+    pub scorecard_inn_2: Option<Scorecard>,
+    #[serde(default)]
+    pub ball: Option<BallEvent>,
+}
+
+impl ServerMessage {
+    /// Protocol version a client must have agreed to in `hello` to be sent
+    /// this; version 0 clients only get what the server sent before there
+    /// was a handshake
+    pub fn min_version(&self) -> u32 {
+        match self {
+            ServerMessage::BallEvent { .. }
+            | ServerMessage::Subscribed { .. }
+            | ServerMessage::Unsubscribed { .. }
+            | ServerMessage::Resync { .. }
+            | ServerMessage::Heartbeat { .. }
+            | ServerMessage::Shutdown { .. }
+            | ServerMessage::Viewers { .. } => 1,
+            // The list and `hello` replies only go to clients that asked
+            _ => 0,
+        }
+    }
+
+    /// Whether a client subscribed with `topics` should receive this message
+    pub fn matches_topics(&self, topics: &[Topic]) -> bool {
+        if topics.is_empty() {
+            return true;
+        }
+
+        match self {
+            ServerMessage::ScoreUpdate { .. } => topics.contains(&Topic::Score),
+            ServerMessage::ScorecardUpdate { .. } => topics.contains(&Topic::Scorecard),
+            ServerMessage::BallEvent { data } => topics.iter().any(|topic| match topic {
+                Topic::BallEvents => true,
+                Topic::Commentary => !data.commentary.is_empty(),
+                Topic::Wickets => data.is_wicket,
+                Topic::Score | Topic::Scorecard | Topic::Viewers => false,
+            }),
+            ServerMessage::Viewers { .. } => topics.contains(&Topic::Viewers),
+            // Full state, errors and list messages always go through
+            _ => true,
+        }
+    }
 }
 
 impl MatchInfo {
@@ -136,10 +460,75 @@ impl MatchInfo {
             toss_decision: hash.get("toss_decision").cloned(),
             stage: hash.get("stage").cloned(),
             group_id: hash.get("group_id").cloned(),
+            tournament_id: hash.get("tournament_id").cloned(),
         })
     }
 }
 
+impl MatchSummary {
+    /// Build a summary from the score and info hashes
+    pub fn from_redis_hashes(
+        match_id: String,
+        score_hash: HashMap<String, String>,
+        info_hash: HashMap<String, String>,
+    ) -> Self {
+        let team_a = info_hash.get("team_a_short").cloned().unwrap_or_default();
+        let team_b = info_hash.get("team_b_short").cloned().unwrap_or_default();
+
+        let runs = score_hash.get("runs").cloned().unwrap_or_default();
+        let wickets = score_hash.get("wickets").cloned().unwrap_or_default();
+        let overs = score_hash.get("overs").cloned().unwrap_or_default();
+        let current_inning = score_hash
+            .get("current_inning")
+            .cloned()
+            .unwrap_or_default();
+
+        let batting_team = score_hash.get("batting_team").cloned().unwrap_or_default();
+
+        // Format scores
+        let (team_a_score, team_b_score) = if current_inning == "1" {
+            if batting_team == info_hash.get("team_a_name").cloned().unwrap_or_default() {
+                (format!("{runs}/{wickets}"), "-".to_string())
+            } else {
+                ("-".to_string(), format!("{runs}/{wickets}"))
+            }
+        } else {
+            // Second inning - need to get first inning score
+            // For simplicity, we'll show current batting score
+            if batting_team == info_hash.get("team_a_name").cloned().unwrap_or_default() {
+                (format!("{runs}/{wickets}"), "-".to_string())
+            } else {
+                ("-".to_string(), format!("{runs}/{wickets}"))
+            }
+        };
+
+        Self {
+            match_id,
+            team_a,
+            team_b,
+            team_a_score,
+            team_b_score,
+            overs,
+            status: score_hash.get("match_status").cloned().unwrap_or_default(),
+            stage: info_hash.get("stage").cloned(),
+            tournament_id: info_hash.get("tournament_id").cloned(),
+            // Filled in from presence counts
+            viewers: 0,
+        }
+    }
+
+    /// Whether a match_status value counts as live
+    pub fn is_live_status(status: &str) -> bool {
+        status == "Live" || status == "in_progress" || status == "active"
+    }
+
+    /// Whether this match belongs in the live matches list
+    pub fn is_live(&self) -> bool {
+        // Matches without a status have always been listed
+        self.status.is_empty() || Self::is_live_status(&self.status)
+    }
+}
+
 impl LiveScore {
     /// Parse LiveScore from Redis hash
     pub fn from_redis_hash(hash: HashMap<String, String>) -> Result<Self, anyhow::Error> {
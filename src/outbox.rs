@@ -0,0 +1,558 @@
+use crate::metrics;
+use crate::models::ServerMessage;
+use crate::websocket::MatchEvent;
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Notify};
+use tracing::{debug, warn};
+
+/// Replies and control frames that may wait for a client before it is
+/// disconnected
+pub const MAX_QUEUED_REPLIES: usize = 64;
+
+/// What to do when a client can't keep up with broadcasts
+#[derive(Debug, Clone, Copy)]
+pub enum SlowClientPolicy {
+    /// Drop superseded updates, keeping the latest state
+    KeepLatest,
+    /// Keep the latest state, but close the connection once the queue has
+    /// been full for this long
+    Disconnect(Duration),
+}
+
+/// An item waiting to be written to a socket
+#[derive(Debug)]
+pub enum Outgoing {
+    /// Reply or control message; not dropped, but bounded
+    Direct(ServerMessage),
+    /// Broadcast for a subscribed match
+    Event { match_id: String, event: MatchEvent },
+    /// Raw frame such as a ping, pong or close
+    Frame(Message),
+}
+
+/// Bounded queue of messages for one connection
+///
+/// Broadcasts are queued up to `capacity`; beyond that, older updates that
+/// a newer one supersedes are dropped first, then the oldest broadcast.
+/// The client is sent a `resync` before the next update for a match it
+/// missed something on. Replies and control frames aren't dropped, but a
+/// client that lets more than `MAX_QUEUED_REPLIES` pile up unread is
+/// disconnected; only the latest pong is kept. Messages newer than the
+/// client's protocol version are never queued.
+pub struct Outbox {
+    inner: Mutex<Inner>,
+    /// Protocol version agreed with the client, 0 until `hello`
+    version: AtomicU32,
+    ready: Notify,
+    closed: watch::Sender<bool>,
+    capacity: usize,
+    policy: SlowClientPolicy,
+}
+
+struct Inner {
+    queue: VecDeque<Outgoing>,
+    /// Broadcasts dropped per match since its last resync
+    skipped: HashMap<String, u64>,
+    /// When the queue filled up, if it hasn't drained since
+    full_since: Option<Instant>,
+    closing: bool,
+    close_frame: Option<CloseFrame<'static>>,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: SlowClientPolicy) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                queue: VecDeque::new(),
+                skipped: HashMap::new(),
+                full_since: None,
+                closing: false,
+                close_frame: None,
+            }),
+            version: AtomicU32::new(0),
+            ready: Notify::new(),
+            closed: watch::Sender::new(false),
+            capacity: capacity.max(1),
+            policy,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Use the protocol version agreed in `hello` from now on
+    pub fn set_version(&self, version: u32) {
+        self.version.store(version, Ordering::Relaxed);
+    }
+
+    /// Whether the client's protocol version has this message type
+    fn understands(&self, message: &ServerMessage) -> bool {
+        message.min_version() <= self.version.load(Ordering::Relaxed)
+    }
+
+    /// Queue a reply or control message
+    pub fn send(&self, message: ServerMessage) {
+        if self.understands(&message) {
+            self.push(Outgoing::Direct(message));
+        }
+    }
+
+    /// Queue a raw frame
+    pub fn send_frame(&self, frame: Message) {
+        self.push(Outgoing::Frame(frame));
+    }
+
+    fn push(&self, item: Outgoing) {
+        let mut inner = self.lock();
+        if inner.closing {
+            return;
+        }
+
+        // A pong only needs to answer the latest ping (RFC 6455 5.5.3)
+        if matches!(item, Outgoing::Frame(Message::Pong(_))) {
+            inner
+                .queue
+                .retain(|queued| !matches!(queued, Outgoing::Frame(Message::Pong(_))));
+        }
+
+        let queued_replies = inner
+            .queue
+            .iter()
+            .filter(|queued| !matches!(queued, Outgoing::Event { .. }))
+            .count();
+        if queued_replies >= MAX_QUEUED_REPLIES {
+            warn!("Disconnecting WebSocket client with too many unread replies");
+            drop(inner);
+            self.close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: "too many unread replies".into(),
+            }));
+            return;
+        }
+
+        inner.queue.push_back(item);
+        drop(inner);
+        self.ready.notify_one();
+    }
+
+    /// Queue a broadcast, applying the slow client policy if the queue is full
+    pub fn send_event(&self, match_id: &str, event: MatchEvent) {
+        if !self.understands(&event.message) {
+            return;
+        }
+        let mut inner = self.lock();
+        if inner.closing {
+            return;
+        }
+
+        let queued_events = inner
+            .queue
+            .iter()
+            .filter(|item| matches!(item, Outgoing::Event { .. }))
+            .count();
+
+        if queued_events >= self.capacity {
+            let full_since = *inner.full_since.get_or_insert_with(Instant::now);
+
+            if let SlowClientPolicy::Disconnect(max_lag) = self.policy {
+                if full_since.elapsed() >= max_lag {
+                    warn!("Disconnecting slow WebSocket client");
+                    drop(inner);
+                    self.close(Some(CloseFrame {
+                        code: close_code::AGAIN,
+                        reason: "client too slow".into(),
+                    }));
+                    return;
+                }
+            }
+
+            inner.make_room(match_id, &event.message);
+        }
+
+        inner.queue.push_back(Outgoing::Event {
+            match_id: match_id.to_string(),
+            event,
+        });
+        drop(inner);
+        self.ready.notify_one();
+    }
+
+    /// Close the connection: pending broadcasts are dropped, queued replies
+    /// are still sent, then the close frame
+    pub fn close(&self, frame: Option<CloseFrame<'static>>) {
+        let mut inner = self.lock();
+        if inner.closing {
+            return;
+        }
+        inner.closing = true;
+        inner.close_frame = frame;
+        inner
+            .queue
+            .retain(|item| !matches!(item, Outgoing::Event { .. }));
+        drop(inner);
+
+        self.closed.send_replace(true);
+        self.ready.notify_one();
+    }
+
+    /// Whether `close` has been called
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    /// Resolves once `close` has been called
+    pub async fn closed(&self) {
+        let mut rx = self.closed.subscribe();
+        let _ = rx.wait_for(|closed| *closed).await;
+    }
+
+    /// Next item to write, or None once closed and drained
+    pub async fn next(&self) -> Option<Outgoing> {
+        loop {
+            // Register before checking so a push in between isn't missed
+            let ready = self.ready.notified();
+
+            {
+                let mut inner = self.lock();
+
+                // Tell the client it missed updates before the next one
+                // arrives, or straight away if none is queued for that match
+                if let Some(match_id) = inner.resync_due() {
+                    if let Some(skipped) = inner.skipped.remove(&match_id) {
+                        let resync = ServerMessage::Resync { match_id, skipped };
+                        if self.understands(&resync) {
+                            return Some(Outgoing::Direct(resync));
+                        }
+                    }
+                }
+
+                if let Some(item) = inner.queue.pop_front() {
+                    if inner.queue.len() < self.capacity {
+                        inner.full_since = None;
+                    }
+                    return Some(item);
+                }
+
+                if inner.closing {
+                    return inner
+                        .close_frame
+                        .take()
+                        .map(|frame| Outgoing::Frame(Message::Close(Some(frame))));
+                }
+            }
+
+            ready.await;
+        }
+    }
+}
+
+impl Inner {
+    /// Match that should get its `resync` now: the one whose update is next,
+    /// or one with nothing left in the queue to wait for
+    fn resync_due(&self) -> Option<String> {
+        if self.skipped.is_empty() {
+            return None;
+        }
+        if let Some(Outgoing::Event { match_id, .. }) = self.queue.front() {
+            if self.skipped.contains_key(match_id) {
+                return Some(match_id.clone());
+            }
+        }
+        self.skipped
+            .keys()
+            .find(|id| {
+                !self
+                    .queue
+                    .iter()
+                    .any(|item| matches!(item, Outgoing::Event { match_id, .. } if match_id == *id))
+            })
+            .cloned()
+    }
+
+    /// Drop queued broadcasts to make space for `incoming`
+    fn make_room(&mut self, match_id: &str, incoming: &ServerMessage) {
+        let before = self.queue.len();
+        self.queue.retain(|item| match item {
+            Outgoing::Event {
+                match_id: id,
+                event,
+            } => id != match_id || !supersedes(incoming, &event.message),
+            _ => true,
+        });
+        let superseded = (before - self.queue.len()) as u64;
+        if superseded > 0 {
+            *self.skipped.entry(match_id.to_string()).or_insert(0) += superseded;
+            metrics::BROADCAST_DROPPED
+                .with_label_values(&["slow_client"])
+                .inc_by(superseded);
+            debug!(
+                "Dropped {} superseded updates for match {} on slow client",
+                superseded, match_id
+            );
+            return;
+        }
+
+        // Nothing superseded: drop the oldest broadcast
+        let oldest = self
+            .queue
+            .iter()
+            .position(|item| matches!(item, Outgoing::Event { .. }));
+        if let Some(Outgoing::Event { match_id: id, .. }) =
+            oldest.and_then(|index| self.queue.remove(index))
+        {
+            *self.skipped.entry(id.clone()).or_insert(0) += 1;
+            metrics::BROADCAST_DROPPED
+                .with_label_values(&["slow_client"])
+                .inc();
+            debug!("Dropped oldest update for match {} on slow client", id);
+        }
+    }
+}
+
+/// Whether `newer` makes `older` redundant for a client that hasn't seen it
+fn supersedes(newer: &ServerMessage, older: &ServerMessage) -> bool {
+    match (newer, older) {
+        (
+            ServerMessage::FullState { .. },
+            ServerMessage::FullState { .. }
+            | ServerMessage::ScoreUpdate { .. }
+            | ServerMessage::ScorecardUpdate { .. }
+            | ServerMessage::Viewers { .. },
+        ) => true,
+        (ServerMessage::ScoreUpdate { .. }, ServerMessage::ScoreUpdate { .. }) => true,
+        (ServerMessage::Viewers { .. }, ServerMessage::Viewers { .. }) => true,
+        (
+            ServerMessage::ScorecardUpdate { inning: new, .. },
+            ServerMessage::ScorecardUpdate { inning: old, .. },
+        ) => new == old,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{BallEvent, LiveScore, Scorecard};
+    use futures::FutureExt;
+
+    fn outbox(capacity: usize) -> Outbox {
+        let outbox = Outbox::new(capacity, SlowClientPolicy::KeepLatest);
+        outbox.set_version(1);
+        outbox
+    }
+
+    fn score(seq: u64) -> MatchEvent {
+        let score = LiveScore::from_redis_hash(HashMap::new()).unwrap();
+        MatchEvent::new(
+            seq,
+            ServerMessage::ScoreUpdate {
+                data: Box::new(score),
+            },
+        )
+    }
+
+    fn scorecard(seq: u64, inning: u8) -> MatchEvent {
+        let data = Scorecard {
+            batsmen: HashMap::new(),
+            bowlers: HashMap::new(),
+        };
+        MatchEvent::new(seq, ServerMessage::ScorecardUpdate { data, inning })
+    }
+
+    fn ball(seq: u64) -> MatchEvent {
+        let ball = BallEvent {
+            inning: 1,
+            over: format!("0.{seq}"),
+            batsman_id: "p1".to_string(),
+            bowler_id: "p9".to_string(),
+            runs: 1,
+            extras: None,
+            is_wicket: false,
+            dismissal: None,
+            commentary: String::new(),
+        };
+        MatchEvent::new(
+            seq,
+            ServerMessage::BallEvent {
+                data: Box::new(ball),
+            },
+        )
+    }
+
+    /// Everything ready to write, described as `kind:match:detail`
+    fn drain(outbox: &Outbox) -> Vec<String> {
+        let mut items = Vec::new();
+        while let Some(Some(item)) = outbox.next().now_or_never() {
+            items.push(match item {
+                Outgoing::Event { match_id, event } => {
+                    let kind = match &*event.message {
+                        ServerMessage::ScoreUpdate { .. } => "score",
+                        ServerMessage::ScorecardUpdate { .. } => "scorecard",
+                        ServerMessage::BallEvent { .. } => "ball",
+                        _ => "other",
+                    };
+                    format!("{kind}:{match_id}:{}", event.seq)
+                }
+                Outgoing::Direct(ServerMessage::Resync { match_id, skipped }) => {
+                    format!("resync:{match_id}:{skipped}")
+                }
+                Outgoing::Direct(message) => format!("direct:{:?}", message.min_version()),
+                Outgoing::Frame(Message::Close(_)) => "close".to_string(),
+                Outgoing::Frame(_) => "frame".to_string(),
+            });
+        }
+        items
+    }
+
+    #[test]
+    fn keeps_order_within_capacity() {
+        let outbox = outbox(3);
+        outbox.send_event("m1", ball(1));
+        outbox.send_event("m1", score(2));
+        outbox.send_event("m1", ball(3));
+        assert_eq!(drain(&outbox), ["ball:m1:1", "score:m1:2", "ball:m1:3"]);
+    }
+
+    #[test]
+    fn overflow_drops_superseded_update_first() {
+        let outbox = outbox(2);
+        outbox.send_event("m1", score(1));
+        outbox.send_event("m1", ball(2));
+        outbox.send_event("m1", score(3));
+        assert_eq!(drain(&outbox), ["resync:m1:1", "ball:m1:2", "score:m1:3"]);
+    }
+
+    #[test]
+    fn overflow_without_superseded_update_drops_oldest() {
+        let outbox = outbox(2);
+        outbox.send_event("m1", ball(1));
+        outbox.send_event("m1", ball(2));
+        outbox.send_event("m1", ball(3));
+        assert_eq!(drain(&outbox), ["resync:m1:1", "ball:m1:2", "ball:m1:3"]);
+    }
+
+    #[test]
+    fn scorecards_only_supersede_the_same_innings() {
+        let outbox = outbox(2);
+        outbox.send_event("m1", scorecard(1, 1));
+        outbox.send_event("m1", scorecard(2, 2));
+        outbox.send_event("m1", scorecard(3, 1));
+        assert_eq!(
+            drain(&outbox),
+            ["resync:m1:1", "scorecard:m1:2", "scorecard:m1:3"]
+        );
+    }
+
+    #[test]
+    fn updates_only_supersede_within_a_match() {
+        let outbox = outbox(2);
+        outbox.send_event("m1", score(1));
+        outbox.send_event("m2", score(1));
+        outbox.send_event("m2", score(2));
+        assert_eq!(drain(&outbox), ["score:m1:1", "resync:m2:1", "score:m2:2"]);
+    }
+
+    #[test]
+    fn resync_sent_even_with_nothing_queued_for_the_match() {
+        let outbox = outbox(1);
+        outbox.send_event("m1", ball(1));
+        outbox.send_event("m2", ball(1));
+        assert_eq!(drain(&outbox), ["resync:m1:1", "ball:m2:1"]);
+        // Nothing is left over to send later
+        outbox.send_event("m1", ball(2));
+        assert_eq!(drain(&outbox), ["ball:m1:2"]);
+    }
+
+    #[test]
+    fn skipped_counts_add_up_until_the_resync() {
+        let outbox = outbox(1);
+        outbox.send_event("m1", score(1));
+        outbox.send_event("m1", score(2));
+        outbox.send_event("m1", score(3));
+        assert_eq!(drain(&outbox), ["resync:m1:2", "score:m1:3"]);
+    }
+
+    #[test]
+    fn replies_are_never_dropped() {
+        let outbox = outbox(1);
+        outbox.send_event("m1", ball(1));
+        outbox.send(ServerMessage::Heartbeat {
+            server_time: 0,
+            interval_secs: 30,
+        });
+        outbox.send_event("m1", ball(2));
+        assert_eq!(drain(&outbox), ["direct:1", "resync:m1:1", "ball:m1:2"]);
+    }
+
+    #[test]
+    fn version_0_clients_get_no_newer_message_types() {
+        let outbox = Outbox::new(1, SlowClientPolicy::KeepLatest);
+        outbox.send_event("m1", ball(1));
+        outbox.send(ServerMessage::Heartbeat {
+            server_time: 0,
+            interval_secs: 30,
+        });
+        outbox.send_event("m1", score(2));
+        outbox.send_event("m1", score(3));
+        assert_eq!(drain(&outbox), ["score:m1:3"]);
+    }
+
+    #[test]
+    fn disconnect_policy_closes_a_full_queue() {
+        let outbox = Outbox::new(1, SlowClientPolicy::Disconnect(Duration::ZERO));
+        outbox.set_version(1);
+        outbox.send_event("m1", ball(1));
+        outbox.send_event("m1", ball(2));
+        assert!(outbox.is_closed());
+        assert_eq!(drain(&outbox), ["close"]);
+        assert!(outbox.next().now_or_never().unwrap().is_none());
+    }
+
+    #[test]
+    fn repeated_pongs_collapse_to_the_latest() {
+        let outbox = outbox(4);
+        for i in 0..1000u32 {
+            outbox.send_frame(Message::Pong(i.to_be_bytes().to_vec()));
+        }
+        assert!(!outbox.is_closed());
+        let Some(Some(Outgoing::Frame(Message::Pong(data)))) = outbox.next().now_or_never() else {
+            panic!("expected a pong");
+        };
+        assert_eq!(data, 999u32.to_be_bytes());
+        assert!(drain(&outbox).is_empty());
+    }
+
+    #[test]
+    fn unread_replies_are_bounded() {
+        let outbox = outbox(4);
+        for _ in 0..1000 {
+            outbox.send(ServerMessage::Heartbeat {
+                server_time: 0,
+                interval_secs: 30,
+            });
+            outbox.send_frame(Message::Ping(Vec::new()));
+        }
+        assert!(outbox.is_closed());
+        let items = drain(&outbox);
+        assert_eq!(items.len(), MAX_QUEUED_REPLIES + 1);
+        assert_eq!(items.last().map(String::as_str), Some("close"));
+    }
+
+    #[test]
+    fn close_keeps_replies_but_drops_broadcasts() {
+        let outbox = outbox(4);
+        outbox.send_event("m1", ball(1));
+        outbox.send(ServerMessage::Heartbeat {
+            server_time: 0,
+            interval_secs: 30,
+        });
+        outbox.close(None);
+        outbox.send_event("m1", ball(2));
+        assert_eq!(drain(&outbox), ["direct:1"]);
+    }
+}
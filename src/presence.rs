@@ -0,0 +1,154 @@
+use crate::models::{unix_millis, NodeInfo, ServerMessage};
+use crate::redis_client::RedisError;
+use crate::websocket::WsState;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// Shares this node's viewer counts with the rest of the cluster
+///
+/// Every interval the node registers itself and writes how many clients
+/// are subscribed to each match. Counts carry an expiry, so a node that
+/// dies without cleaning up stops being counted after `ttl`.
+pub struct Presence {
+    node_id: String,
+    state: WsState,
+    interval: Duration,
+    ttl: Duration,
+    started_at: u64,
+    /// Counts written last time, to clear once a match has no viewers
+    reported: Mutex<HashMap<String, u64>>,
+}
+
+impl Presence {
+    pub fn new(node_id: String, state: WsState, interval: Duration, ttl: Duration) -> Self {
+        Self {
+            node_id,
+            state,
+            interval,
+            ttl,
+            started_at: unix_millis(),
+            reported: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Report counts every interval until the task is aborted
+    pub async fn run(self: Arc<Self>) {
+        info!("Registering node {} for presence", self.node_id);
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.report().await {
+                warn!("Failed to report presence: {}", e);
+            }
+        }
+    }
+
+    async fn report(&self) -> Result<(), RedisError> {
+        let counts: HashMap<String, u64> = self
+            .state
+            .match_stats()
+            .await
+            .into_iter()
+            .filter(|stats| stats.subscribers > 0)
+            .map(|stats| (stats.match_id, stats.subscribers as u64))
+            .collect();
+
+        let mut reported = self.reported.lock().await;
+        let gone: Vec<String> = reported
+            .keys()
+            .filter(|match_id| !counts.contains_key(*match_id))
+            .cloned()
+            .collect();
+
+        let now = unix_millis();
+        let expires_at = now + self.ttl.as_millis() as u64;
+        self.state
+            .redis
+            .report_viewers(&self.node_id, &counts, &gone, expires_at, self.ttl)
+            .await?;
+        *reported = counts.clone();
+        drop(reported);
+
+        let node = NodeInfo {
+            node_id: self.node_id.clone(),
+            started_at: self.started_at,
+            updated_at: now,
+            connections: self.state.connection_count().await,
+            matches: counts.len(),
+            viewers: counts.values().sum(),
+        };
+        self.state.redis.register_node(&node, self.ttl).await
+    }
+
+    /// Push a `viewers` message to each watched match every `interval`,
+    /// skipping matches whose count hasn't changed since the last push
+    pub async fn push_viewers(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut pushed: HashMap<String, u64> = HashMap::new();
+        loop {
+            ticker.tick().await;
+            let mut counts = HashMap::new();
+            for stats in self.state.match_stats().await {
+                if stats.subscribers == 0 {
+                    continue;
+                }
+                let viewers = self
+                    .viewers(&stats.match_id, stats.subscribers as u64)
+                    .await;
+                if pushed.get(&stats.match_id) != Some(&viewers) {
+                    self.state
+                        .broadcast_transient(
+                            &stats.match_id,
+                            ServerMessage::Viewers {
+                                match_id: stats.match_id.clone(),
+                                viewers,
+                            },
+                        )
+                        .await;
+                }
+                counts.insert(stats.match_id, viewers);
+            }
+            pushed = counts;
+        }
+    }
+
+    /// Viewers across the cluster, or just `local` if Redis can't say.
+    /// This node's share of the cluster total is swapped for `local`, which
+    /// may be newer than what was last reported.
+    async fn viewers(&self, match_id: &str, local: u64) -> u64 {
+        match self.state.redis.get_viewers(match_id).await {
+            Ok(cluster) => {
+                let own = self.reported.lock().await.get(match_id).copied();
+                cluster.saturating_sub(own.unwrap_or(0)) + local
+            }
+            Err(e) => {
+                debug!("Using local viewer count for match {}: {}", match_id, e);
+                local
+            }
+        }
+    }
+
+    /// Remove this node and its counts, e.g. at shutdown
+    pub async fn leave(&self) -> Result<(), RedisError> {
+        let reported: Vec<String> = self
+            .reported
+            .lock()
+            .await
+            .drain()
+            .map(|(match_id, _)| match_id)
+            .collect();
+        self.state
+            .redis
+            .deregister_node(&self.node_id, &reported)
+            .await
+    }
+}
@@ -1,11 +1,24 @@
-use crate::models::ServerMessage;
+use crate::coalesce::Coalescer;
+use crate::health::{ListenerState, ListenerStatus};
+use crate::metrics;
+use crate::models::{
+    BallEvent, LiveScore, MatchUpdate, Scorecard, ServerMessage, UpdateSection,
+    UPDATE_PAYLOAD_VERSION,
+};
+use crate::redis_client::{RedisClient, RedisError};
+use crate::streams::{EntryId, StreamLayout};
 use crate::websocket::WsState;
 use anyhow::{Context, Result};
 use futures::StreamExt;
+use std::collections::HashMap;
 use tracing::{debug, error, info, warn};
 
 /// Start the Redis Pub/Sub listener
-pub async fn start_pubsub_listener(redis_url: &str, ws_state: WsState) -> Result<()> {
+pub async fn start_pubsub_listener(
+    redis_url: &str,
+    coalescer: Coalescer,
+    status: &ListenerStatus,
+) -> Result<()> {
     let client = redis::Client::open(redis_url).context("Failed to create Redis client")?;
     let mut pubsub = client
         .get_async_pubsub()
@@ -19,6 +32,10 @@ pub async fn start_pubsub_listener(redis_url: &str, ws_state: WsState) -> Result
         .context("Failed to subscribe to pattern")?;
 
     info!("Redis Pub/Sub listener started, listening for match_updates:*");
+    status.set_state(ListenerState::Connected);
+
+    // Last sequence number seen per match, used to drop stale payloads
+    let mut last_seq: HashMap<String, u64> = HashMap::new();
 
     // Start listening for messages
     let mut stream = pubsub.on_message();
@@ -27,11 +44,11 @@ pub async fn start_pubsub_listener(redis_url: &str, ws_state: WsState) -> Result
         // Wrap the entire message handling in a catch block to prevent crashes
         let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
             let channel = msg.get_channel_name();
-            (channel.to_string(), msg)
+            (channel.to_string(), msg.get_payload_bytes().to_vec())
         }));
 
-        let (channel, _msg) = match result {
-            Ok((ch, m)) => (ch, m),
+        let (channel, payload) = match result {
+            Ok((ch, p)) => (ch, p),
             Err(e) => {
                 error!("Panic while processing message: {:?}", e);
                 continue;
@@ -39,6 +56,10 @@ pub async fn start_pubsub_listener(redis_url: &str, ws_state: WsState) -> Result
         };
 
         debug!("Received message on channel: {}", channel);
+        metrics::UPDATES_RECEIVED
+            .with_label_values(&["pubsub"])
+            .inc();
+        status.record_message();
 
         // Extract match_id from channel name (match_updates:{match_id})
         let parts: Vec<&str> = channel.split(':').collect();
@@ -49,62 +70,349 @@ pub async fn start_pubsub_listener(redis_url: &str, ws_state: WsState) -> Result
 
         let match_id = parts[1];
 
-        // Fetch updated score from Redis with error handling
-        match ws_state.redis.get_live_score(match_id).await {
-            Ok(score) => {
-                debug!("Fetched updated score for match: {}", match_id);
+        handle_update(&coalescer, match_id, &payload, &mut last_seq, Vec::new()).await;
+    }
+
+    warn!("Pub/Sub stream ended");
+    Ok(())
+}
+
+/// Where updates are published for the nodes to read
+#[derive(Debug, Clone)]
+pub enum UpdateFeed {
+    PubSub,
+    Streams(StreamLayout),
+}
+
+impl UpdateFeed {
+    /// Publish an update the way writers do, so every node handles it
+    pub async fn publish(
+        &self,
+        redis: &RedisClient,
+        match_id: &str,
+        payload: &str,
+    ) -> Result<(), RedisError> {
+        match self {
+            UpdateFeed::PubSub => {
+                redis
+                    .publish(&format!("match_updates:{match_id}"), payload)
+                    .await
+            }
+            UpdateFeed::Streams(StreamLayout::Global(key)) => {
+                redis
+                    .xadd(key, &[("match_id", match_id), ("payload", payload)])
+                    .await
+            }
+            UpdateFeed::Streams(StreamLayout::PerMatch) => {
+                redis
+                    .xadd(
+                        &format!("match:{match_id}:updates"),
+                        &[("payload", payload)],
+                    )
+                    .await
+            }
+        }
+    }
+}
+
+/// Payload making every node re-read a match and send a fresh `full_state`
+pub fn full_state_payload() -> String {
+    serde_json::json!({
+        "v": UPDATE_PAYLOAD_VERSION,
+        "event": "correction",
+        "sections": ["info"]
+    })
+    .to_string()
+}
+
+/// Data for one section of the match state, either pushed by the writer
+/// or to be re-read from Redis
+#[derive(Debug, Clone)]
+pub enum SectionUpdate<T> {
+    Carried(T),
+    Fetch,
+}
+
+impl<T> SectionUpdate<T> {
+    /// None if the section didn't change, otherwise the carried data or a fetch
+    fn for_section(
+        sections: &[UpdateSection],
+        section: UpdateSection,
+        data: Option<T>,
+    ) -> Option<Self> {
+        sections
+            .contains(&section)
+            .then(|| data.map_or(SectionUpdate::Fetch, SectionUpdate::Carried))
+    }
+}
+
+/// Everything that needs broadcasting for a match, possibly merged from
+/// several updates
+#[derive(Debug, Clone, Default)]
+pub struct PendingUpdate {
+    pub balls: Vec<BallEvent>,
+    pub info: bool,
+    pub score: Option<SectionUpdate<LiveScore>>,
+    pub scorecards: [Option<SectionUpdate<Scorecard>>; 2],
+    /// Stream entries this came from, acknowledged once it is applied
+    pub acks: Vec<EntryId>,
+}
+
+impl PendingUpdate {
+    /// Legacy behaviour: re-read score and both scorecards
+    pub fn refetch_all() -> Self {
+        Self {
+            score: Some(SectionUpdate::Fetch),
+            scorecards: [Some(SectionUpdate::Fetch), Some(SectionUpdate::Fetch)],
+            ..Self::default()
+        }
+    }
+
+    /// Build from a parsed payload
+    pub fn from_payload(update: MatchUpdate) -> Self {
+        let mut sections = update.sections;
+
+        // Writers may omit `sections` and just carry the data
+        if sections.is_empty() {
+            if update.score.is_some() {
+                sections.push(UpdateSection::Score);
+            }
+            if update.scorecard_inn_1.is_some() {
+                sections.push(UpdateSection::Scorecard1);
+            }
+            if update.scorecard_inn_2.is_some() {
+                sections.push(UpdateSection::Scorecard2);
+            }
+        }
+
+        if sections.is_empty() && update.ball.is_none() {
+            return Self::refetch_all();
+        }
+
+        Self {
+            score: SectionUpdate::for_section(&sections, UpdateSection::Score, update.score),
+            scorecards: [
+                SectionUpdate::for_section(
+                    &sections,
+                    UpdateSection::Scorecard1,
+                    update.scorecard_inn_1,
+                ),
+                SectionUpdate::for_section(
+                    &sections,
+                    UpdateSection::Scorecard2,
+                    update.scorecard_inn_2,
+                ),
+            ],
+            info: sections.contains(&UpdateSection::Info),
+            balls: update.ball.into_iter().collect(),
+            acks: Vec::new(),
+        }
+    }
+
+    /// Fold a later update into this one; newer section data wins and
+    /// ball events are kept in order
+    pub fn merge(&mut self, later: PendingUpdate) {
+        self.balls.extend(later.balls);
+        self.info |= later.info;
+        self.acks.extend(later.acks);
+        if later.score.is_some() {
+            self.score = later.score;
+        }
+        for (current, newer) in self.scorecards.iter_mut().zip(later.scorecards) {
+            if newer.is_some() {
+                *current = newer;
+            }
+        }
+    }
+}
+
+/// Process one update for a match, broadcasting the carried data when the
+/// payload has it and falling back to re-reading Redis otherwise
+///
+/// `acks` are the stream entries the payload came from, if any; they are
+/// acknowledged once the update is applied, or straight away if dropped.
+pub async fn handle_update(
+    coalescer: &Coalescer,
+    match_id: &str,
+    payload: &[u8],
+    last_seq: &mut HashMap<String, u64>,
+    acks: Vec<EntryId>,
+) {
+    let Some(update) = parse_update(match_id, payload) else {
+        let pending = PendingUpdate {
+            acks,
+            ..PendingUpdate::refetch_all()
+        };
+        coalescer.submit(match_id, pending).await;
+        return;
+    };
+
+    // Drop payloads that arrive after a newer one for the same match
+    if update.seq != 0 {
+        let last = last_seq.entry(match_id.to_string()).or_insert(0);
+        if update.seq <= *last {
+            debug!(
+                "Dropping stale update for match {} (seq {} <= {})",
+                match_id, update.seq, last
+            );
+            coalescer.acknowledge(acks).await;
+            return;
+        }
+        *last = update.seq;
+    }
+
+    let pending = PendingUpdate {
+        acks,
+        ..PendingUpdate::from_payload(update)
+    };
+    coalescer.submit(match_id, pending).await;
+}
+
+/// Parse a pub/sub payload, returning None when the legacy re-fetch should be used
+fn parse_update(match_id: &str, payload: &[u8]) -> Option<MatchUpdate> {
+    if payload.iter().all(u8::is_ascii_whitespace) {
+        return None;
+    }
+
+    match serde_json::from_slice::<MatchUpdate>(payload) {
+        Ok(update) if update.v == UPDATE_PAYLOAD_VERSION => Some(update),
+        Ok(update) => {
+            warn!(
+                "Unsupported update payload version {} for match {}, re-fetching",
+                update.v, match_id
+            );
+            None
+        }
+        Err(e) => {
+            warn!(
+                "Invalid update payload for match {}: {}. Re-fetching",
+                match_id, e
+            );
+            None
+        }
+    }
+}
 
-                // Broadcast score update to all subscribers
-                let score_message = ServerMessage::ScoreUpdate { data: Box::new(score) };
-                ws_state.broadcast(match_id, score_message).await;
+/// Broadcast a pending update to subscribers of a match
+pub async fn apply_update(ws_state: &WsState, match_id: &str, mut update: PendingUpdate) {
+    let summary_changed = update.info || update.score.is_some();
 
-                debug!("Broadcasted score update for match: {}", match_id);
+    for ball in std::mem::take(&mut update.balls) {
+        let ball_message = ServerMessage::BallEvent {
+            data: Box::new(ball),
+        };
+        ws_state.broadcast(match_id, ball_message).await;
+    }
+
+    // Static info changed: clients need the whole state again
+    if update.info {
+        match ws_state.redis.get_full_match_state(match_id).await {
+            Ok(full_state) => {
+                let message = ServerMessage::FullState {
+                    data: Box::new(full_state),
+                };
+                ws_state.broadcast(match_id, message).await;
+                debug!("Broadcasted full state for match: {}", match_id);
             }
             Err(e) => {
-                error!("Failed to fetch score for match {}: {}", match_id, e);
-                // Don't break the loop - continue processing other messages
+                error!("Failed to fetch full state for match {}: {}", match_id, e);
             }
         }
+    } else {
+        broadcast_sections(ws_state, match_id, update).await;
+    }
+
+    if summary_changed {
+        ws_state.update_list_entry(match_id).await;
+    }
+}
 
-        // Fetch and broadcast scorecard updates for both innings
-        for inning in 1..=2 {
-            match ws_state.redis.get_scorecard(match_id, inning).await {
-                Ok(Some(scorecard)) => {
-                    debug!(
-                        "Fetched scorecard for match: {}, inning: {}",
-                        match_id, inning
-                    );
-
-                    // Broadcast scorecard update
-                    let scorecard_message = ServerMessage::ScorecardUpdate {
-                        data: scorecard,
-                        inning,
-                    };
-                    ws_state.broadcast(match_id, scorecard_message).await;
-
-                    debug!(
-                        "Broadcasted scorecard update for match: {}, inning: {}",
-                        match_id, inning
-                    );
-                }
-                Ok(None) => {
-                    // No scorecard for this inning yet - this is normal
-                    debug!(
-                        "No scorecard available for match: {}, inning: {}",
-                        match_id, inning
-                    );
-                }
-                Err(e) => {
-                    error!(
-                        "Failed to fetch scorecard for match {}, inning {}: {}",
-                        match_id, inning, e
-                    );
-                    // Don't break the loop - continue processing
-                }
+/// Broadcast the score and scorecard sections of an update
+async fn broadcast_sections(ws_state: &WsState, match_id: &str, update: PendingUpdate) {
+    match update.score {
+        Some(SectionUpdate::Carried(score)) => {
+            let score_message = ServerMessage::ScoreUpdate {
+                data: Box::new(score),
+            };
+            ws_state.broadcast(match_id, score_message).await;
+            debug!("Broadcasted pushed score update for match: {}", match_id);
+        }
+        Some(SectionUpdate::Fetch) => fetch_and_broadcast_score(ws_state, match_id).await,
+        None => {}
+    }
+
+    for (inning, scorecard) in (1..=2).zip(update.scorecards) {
+        match scorecard {
+            Some(SectionUpdate::Carried(scorecard)) => {
+                broadcast_scorecard(ws_state, match_id, inning, scorecard).await
             }
+            Some(SectionUpdate::Fetch) => {
+                fetch_and_broadcast_scorecard(ws_state, match_id, inning).await
+            }
+            None => {}
         }
     }
+}
 
-    warn!("Pub/Sub stream ended");
-    Ok(())
+/// Fetch the live score from Redis and broadcast it
+async fn fetch_and_broadcast_score(ws_state: &WsState, match_id: &str) {
+    // Fetch updated score from Redis with error handling
+    match ws_state.redis.get_live_score(match_id).await {
+        Ok(score) => {
+            debug!("Fetched updated score for match: {}", match_id);
+
+            // Broadcast score update to all subscribers
+            let score_message = ServerMessage::ScoreUpdate {
+                data: Box::new(score),
+            };
+            ws_state.broadcast(match_id, score_message).await;
+
+            debug!("Broadcasted score update for match: {}", match_id);
+        }
+        Err(e) => {
+            error!("Failed to fetch score for match {}: {}", match_id, e);
+            // Don't break the loop - continue processing other messages
+        }
+    }
+}
+
+/// Fetch a scorecard from Redis and broadcast it if present
+async fn fetch_and_broadcast_scorecard(ws_state: &WsState, match_id: &str, inning: u8) {
+    match ws_state.redis.get_scorecard(match_id, inning).await {
+        Ok(Some(scorecard)) => {
+            debug!(
+                "Fetched scorecard for match: {}, inning: {}",
+                match_id, inning
+            );
+            broadcast_scorecard(ws_state, match_id, inning, scorecard).await;
+        }
+        Ok(None) => {
+            // No scorecard for this inning yet - this is normal
+            debug!(
+                "No scorecard available for match: {}, inning: {}",
+                match_id, inning
+            );
+        }
+        Err(e) => {
+            error!(
+                "Failed to fetch scorecard for match {}, inning {}: {}",
+                match_id, inning, e
+            );
+            // Don't break the loop - continue processing
+        }
+    }
+}
+
+/// Broadcast a scorecard update
+async fn broadcast_scorecard(ws_state: &WsState, match_id: &str, inning: u8, scorecard: Scorecard) {
+    let scorecard_message = ServerMessage::ScorecardUpdate {
+        data: scorecard,
+        inning,
+    };
+    ws_state.broadcast(match_id, scorecard_message).await;
+
+    debug!(
+        "Broadcasted scorecard update for match: {}, inning: {}",
+        match_id, inning
+    );
 }
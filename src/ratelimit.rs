@@ -0,0 +1,382 @@
+use crate::auth::Grant;
+use anyhow::{Context, Result};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// How often idle buckets are swept out
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Per-client token buckets
+///
+/// Each key gets `burst` tokens, refilled at `per_sec`; a request takes one.
+#[derive(Clone)]
+pub struct RateLimiter {
+    per_sec: f64,
+    burst: f64,
+    inner: Arc<Mutex<Buckets>>,
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    last_prune: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// A `per_sec` of 0 disables limiting
+    pub fn new(per_sec: f64, burst: u32) -> Self {
+        Self {
+            per_sec,
+            burst: f64::from(burst.max(1)),
+            inner: Arc::new(Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                last_prune: Instant::now(),
+            })),
+        }
+    }
+
+    /// Take a token for `key`, or say how long until one is available
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        if self.per_sec <= 0.0 {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Full buckets hold no state worth keeping
+        if now.duration_since(inner.last_prune) >= PRUNE_INTERVAL {
+            let (per_sec, burst) = (self.per_sec, self.burst);
+            inner.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_sec < burst
+            });
+            inner.last_prune = now;
+        }
+
+        let bucket = inner.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_sec).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_sec,
+            ))
+        }
+    }
+}
+
+/// Key requests are limited by: the IP, plus the credential if there is one
+///
+/// An API key may be embedded in a page and shared by many end users, so a
+/// credential alone would put all of them in one bucket.
+pub fn rate_limit_key(grant: &Grant, ip: IpAddr) -> String {
+    if grant.is_anonymous() || grant.name.is_empty() {
+        format!("ip:{ip}")
+    } else {
+        format!("client:{}@{ip}", grant.name)
+    }
+}
+
+/// Address of the client, after unwrapping trusted proxies
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// Proxies whose `X-Forwarded-For` header is believed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    /// Network address and prefix length
+    networks: Arc<Vec<(IpAddr, u8)>>,
+}
+
+impl TrustedProxies {
+    /// Parse a list of IPs and CIDR ranges
+    pub fn parse(values: &[String]) -> Result<Self> {
+        let mut networks = Vec::new();
+        for item in values.iter().map(|item| item.trim()) {
+            let (addr, prefix) = match item.split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix)),
+                None => (item, None),
+            };
+            let addr: IpAddr = addr
+                .parse()
+                .with_context(|| format!("Invalid proxy address {item:?}"))?;
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => prefix
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|prefix| *prefix <= max)
+                    .with_context(|| format!("Invalid prefix length in {item:?}"))?,
+                None => max,
+            };
+            networks.push((addr, prefix));
+        }
+        Ok(Self {
+            networks: Arc::new(networks),
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(network, prefix)| match (network, ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                    u32::from(*network) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                    u128::from(*network) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            })
+    }
+
+    /// Work out the client's address from the peer and `X-Forwarded-For`
+    ///
+    /// Walks the header from the right, skipping trusted proxies, so a
+    /// client can't pick its own address by sending the header itself.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|hop| hop.trim().parse().ok())
+            .collect();
+
+        forwarded
+            .iter()
+            .rev()
+            .find(|hop| !self.contains(**hop))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+}
+
+/// State for the HTTP rate limiting middleware
+#[derive(Clone)]
+pub struct HttpRateLimit {
+    pub limiter: RateLimiter,
+    pub proxies: TrustedProxies,
+}
+
+/// Middleware limiting request rates per client
+///
+/// Runs after authentication so clients with credentials get their own
+/// bucket per IP rather than sharing the anonymous one. Adds `ClientIp` to
+/// the request extensions.
+pub async fn rate_limit(
+    State(limits): State<HttpRateLimit>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let ip = limits.proxies.client_ip(peer.ip(), req.headers());
+    req.extensions_mut().insert(ClientIp(ip));
+
+    let key = match req.extensions().get::<Grant>() {
+        Some(grant) => rate_limit_key(grant, ip),
+        None => format!("ip:{ip}"),
+    };
+
+    match limits.limiter.check(&key) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            debug!("Rate limited {} on {}", key, req.uri().path());
+            let secs = retry_after.as_secs_f64().ceil() as u64;
+            let mut response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(json!({
+                    "error": "Too many requests",
+                    "retry_after_secs": secs
+                })),
+            )
+                .into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies(values: &[&str]) -> TrustedProxies {
+        let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+        TrustedProxies::parse(&values).unwrap()
+    }
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_rejects_bad_entries() {
+        for bad in [
+            "nonsense",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/x",
+            "10.0.0/8",
+        ] {
+            assert!(
+                TrustedProxies::parse(&[bad.to_string()]).is_err(),
+                "{bad} should not parse"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_trims_and_defaults_to_single_address() {
+        let proxies = proxies(&[" 10.1.2.3 ", "fd00::1"]);
+        assert!(proxies.contains(ip("10.1.2.3")));
+        assert!(!proxies.contains(ip("10.1.2.4")));
+        assert!(proxies.contains(ip("fd00::1")));
+        assert!(!proxies.contains(ip("fd00::2")));
+    }
+
+    #[test]
+    fn contains_handles_prefix_edges() {
+        let all_v4 = proxies(&["0.0.0.0/0"]);
+        assert!(all_v4.contains(ip("203.0.113.9")));
+        assert!(!all_v4.contains(ip("::1")));
+
+        let all_v6 = proxies(&["::/0"]);
+        assert!(all_v6.contains(ip("2001:db8::1")));
+        assert!(!all_v6.contains(ip("127.0.0.1")));
+
+        let single = proxies(&["192.168.1.10/32"]);
+        assert!(single.contains(ip("192.168.1.10")));
+        assert!(!single.contains(ip("192.168.1.11")));
+
+        let range = proxies(&["172.16.0.0/12"]);
+        assert!(range.contains(ip("172.31.255.255")));
+        assert!(!range.contains(ip("172.32.0.0")));
+    }
+
+    #[test]
+    fn client_ip_ignores_header_from_untrusted_peer() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = forwarded_for("198.51.100.7");
+        assert_eq!(
+            proxies.client_ip(ip("203.0.113.1"), &headers),
+            ip("203.0.113.1")
+        );
+    }
+
+    #[test]
+    fn client_ip_ignores_spoofed_leftmost_hop() {
+        let proxies = proxies(&["127.0.0.1", "10.0.0.0/8"]);
+        // The client sent its own header; the proxies appended the real address
+        let headers = forwarded_for("1.2.3.4, 198.51.100.7, 10.0.0.5");
+        assert_eq!(
+            proxies.client_ip(ip("127.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn client_ip_reads_every_header_line() {
+        let proxies = proxies(&["127.0.0.1"]);
+        let mut headers = forwarded_for("1.2.3.4");
+        headers.append("x-forwarded-for", HeaderValue::from_static("198.51.100.7"));
+        assert_eq!(
+            proxies.client_ip(ip("127.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn client_ip_with_all_hops_trusted_uses_leftmost() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = forwarded_for("10.0.0.9, garbage, 10.0.0.5");
+        assert_eq!(proxies.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.9"));
+    }
+
+    #[test]
+    fn client_ip_without_header_is_peer() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        assert_eq!(
+            proxies.client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn check_allows_burst_then_limits() {
+        let limiter = RateLimiter::new(1.0, 2);
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let retry_after = limiter.check("a").unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+        // Other keys have their own bucket
+        assert!(limiter.check("b").is_ok());
+    }
+
+    #[test]
+    fn check_refills_over_time() {
+        let limiter = RateLimiter::new(1000.0, 1);
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_err());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(limiter.check("a").is_ok());
+    }
+
+    #[test]
+    fn check_with_zero_rate_is_disabled() {
+        let limiter = RateLimiter::new(0.0, 1);
+        for _ in 0..100 {
+            assert!(limiter.check("a").is_ok());
+        }
+    }
+
+    #[test]
+    fn key_separates_credentials_by_ip() {
+        let grant: Grant = serde_json::from_value(json!({ "name": "partner" })).unwrap();
+        assert_eq!(
+            rate_limit_key(&grant, ip("198.51.100.7")),
+            "client:partner@198.51.100.7"
+        );
+        assert_ne!(
+            rate_limit_key(&grant, ip("198.51.100.7")),
+            rate_limit_key(&grant, ip("198.51.100.8"))
+        );
+    }
+}
@@ -1,232 +1,527 @@
-use crate::models::{FullMatchState, LiveScore, MatchInfo, MatchSummary, Scorecard};
-use anyhow::{Context, Result};
+use crate::metrics;
+use crate::models::{
+    unix_millis, ErrorCode, FullMatchState, LiveScore, MatchInfo, MatchSummary, NodeInfo, Scorecard,
+};
 use redis::{aio::ConnectionManager, AsyncCommands};
 use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use thiserror::Error;
 use tracing::debug;
 
+/// Errors from reading match data
+#[derive(Debug, Error)]
+pub enum RedisError {
+    #[error("Failed to connect to Redis: {0}")]
+    Connect(#[source] redis::RedisError),
+    #[error("{context}: {source}")]
+    Command {
+        context: &'static str,
+        source: redis::RedisError,
+    },
+    #[error("Match not found: {0}")]
+    MatchNotFound(String),
+    #[error("Invalid match data: {0}")]
+    InvalidData(String),
+}
+
+impl RedisError {
+    /// Code reported to clients; everything but a missing match is internal
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::MatchNotFound(_) => ErrorCode::MatchNotFound,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    fn invalid_data(e: anyhow::Error) -> Self {
+        Self::InvalidData(format!("{e:#}"))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, RedisError>;
+
+/// Label a failed command with what it was doing
+fn command(context: &'static str) -> impl FnOnce(redis::RedisError) -> RedisError {
+    move |source| RedisError::Command { context, source }
+}
+
+/// Run an operation, recording how long it took
+async fn timed<T>(operation: &'static str, future: impl Future<Output = Result<T>>) -> Result<T> {
+    let started = Instant::now();
+    let result = future.await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    metrics::REDIS_COMMAND_DURATION
+        .with_label_values(&[operation, outcome])
+        .observe(started.elapsed().as_secs_f64());
+    result
+}
+
+/// Per-node viewer counts for a match: node_id -> "{count}:{expires_at_ms}"
+fn viewers_key(match_id: &str) -> String {
+    format!("match:{match_id}:viewers")
+}
+
+/// Registration of a backend node, as JSON
+fn node_key(node_id: &str) -> String {
+    format!("backend:node:{node_id}")
+}
+
+/// IDs of registered nodes, so they can be listed without scanning keys
+const NODES_KEY: &str = "backend:nodes";
+
+/// Sum the unexpired viewer counts for a match, pruning counts left by
+/// nodes that stopped without cleaning up
+async fn read_viewers(conn: &mut ConnectionManager, match_id: &str) -> Result<u64> {
+    let key = viewers_key(match_id);
+    let counts: HashMap<String, String> = conn
+        .hgetall(&key)
+        .await
+        .map_err(command("Failed to get viewers"))?;
+
+    let now = unix_millis();
+    let mut total = 0;
+    let mut expired = Vec::new();
+    for (node_id, value) in counts {
+        let parsed = value.split_once(':').and_then(|(count, expires_at)| {
+            Some((count.parse::<u64>().ok()?, expires_at.parse::<u64>().ok()?))
+        });
+        match parsed {
+            Some((count, expires_at)) if expires_at > now => total += count,
+            _ => expired.push(node_id),
+        }
+    }
+
+    if !expired.is_empty() {
+        let _: redis::RedisResult<()> = conn.hdel(&key, &expired).await;
+    }
+    Ok(total)
+}
+
 /// Redis client for fetching match data
 #[derive(Clone)]
 pub struct RedisClient {
     conn: ConnectionManager,
+    /// Attempts for reads made with retry
+    retries: u32,
+    retry_delay: Duration,
 }
 
 impl RedisClient {
-    /// Create a new Redis client
-    pub async fn new(redis_url: &str) -> Result<Self> {
-        let client = redis::Client::open(redis_url).context("Failed to create Redis client")?;
+    /// Create a new Redis client, retrying reads up to `retries` times
+    pub async fn new(redis_url: &str, retries: u32, retry_delay: Duration) -> Result<Self> {
+        let client = redis::Client::open(redis_url).map_err(RedisError::Connect)?;
 
         let conn = ConnectionManager::new(client)
             .await
-            .context("Failed to connect to Redis")?;
+            .map_err(RedisError::Connect)?;
 
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            retries: retries.max(1),
+            retry_delay,
+        })
     }
 
-    /// Get all live matches with retry logic
-    pub async fn get_live_matches(&self) -> Result<Vec<MatchSummary>> {
-        self.with_retry(|| async {
+    /// Check that Redis answers
+    pub async fn ping(&self) -> Result<()> {
+        timed("ping", async {
             let mut conn = self.conn.clone();
-            let mut matches = Vec::new();
-
-            // Use KEYS command to find all match:*:score keys
-            // Note: In production, use SCAN for large datasets
-            let keys: Vec<String> = conn
-                .keys("match:*:score")
+            redis::cmd("PING")
+                .query_async::<String>(&mut conn)
                 .await
-                .context("Failed to get match keys")?;
+                .map_err(command("Failed to ping Redis"))?;
+            Ok(())
+        })
+        .await
+    }
 
-            for key in keys {
-                // Extract match_id from key (match:{match_id}:score)
-                let parts: Vec<&str> = key.split(':').collect();
-                if parts.len() != 3 {
-                    continue;
-                }
-                let match_id = parts[1].to_string();
+    /// Get all live matches with retry logic
+    pub async fn get_live_matches(&self) -> Result<Vec<MatchSummary>> {
+        timed("get_live_matches", async {
+            self.with_retry("get_live_matches", || async {
+                let mut conn = self.conn.clone();
+                let mut matches = Vec::new();
+
+                // Use KEYS command to find all match:*:score keys
+                // Note: In production, use SCAN for large datasets
+                let keys: Vec<String> = conn
+                    .keys("match:*:score")
+                    .await
+                    .map_err(command("Failed to get match keys"))?;
+
+                for key in keys {
+                    // Extract match_id from key (match:{match_id}:score)
+                    let parts: Vec<&str> = key.split(':').collect();
+                    if parts.len() != 3 {
+                        continue;
+                    }
+                    let match_id = parts[1].to_string();
 
-                // Get the score hash
-                let score_hash: HashMap<String, String> =
-                    conn.hgetall(&key).await.unwrap_or_default();
+                    // Get the score hash
+                    let score_hash: HashMap<String, String> =
+                        conn.hgetall(&key).await.unwrap_or_default();
 
-                // Only include "Live" matches
-                if let Some(status) = score_hash.get("match_status") {
-                    if status != "Live" && status != "in_progress" && status != "active" {
-                        continue;
+                    // Only include "Live" matches
+                    if let Some(status) = score_hash.get("match_status") {
+                        if !MatchSummary::is_live_status(status) {
+                            continue;
+                        }
                     }
+
+                    // Get match info
+                    let info_key = format!("match:{match_id}:info");
+                    let info_hash: HashMap<String, String> =
+                        conn.hgetall(&info_key).await.unwrap_or_default();
+
+                    let viewers = read_viewers(&mut conn, &match_id).await.unwrap_or_default();
+                    let mut summary =
+                        MatchSummary::from_redis_hashes(match_id, score_hash, info_hash);
+                    summary.viewers = viewers;
+                    matches.push(summary);
                 }
+                debug!("Found {} live matches", matches.len());
+                Ok(matches)
+            })
+            .await
+        })
+        .await
+    }
 
-                // Get match info
-                let info_key = format!("match:{match_id}:info");
-                let info_hash: HashMap<String, String> =
-                    conn.hgetall(&info_key).await.unwrap_or_default();
-
-                // Build match summary
-                let team_a = info_hash.get("team_a_short").cloned().unwrap_or_default();
-                let team_b = info_hash.get("team_b_short").cloned().unwrap_or_default();
-
-                let runs = score_hash.get("runs").cloned().unwrap_or_default();
-                let wickets = score_hash.get("wickets").cloned().unwrap_or_default();
-                let overs = score_hash.get("overs").cloned().unwrap_or_default();
-                let current_inning = score_hash
-                    .get("current_inning")
-                    .cloned()
-                    .unwrap_or_default();
-
-                let batting_team = score_hash.get("batting_team").cloned().unwrap_or_default();
-
-                // Format scores
-                let (team_a_score, team_b_score) = if current_inning == "1" {
-                    if batting_team == info_hash.get("team_a_name").cloned().unwrap_or_default() {
-                        (format!("{runs}/{wickets}"), "-".to_string())
-                    } else {
-                        ("-".to_string(), format!("{runs}/{wickets}"))
-                    }
-                } else {
-                    // Second inning - need to get first inning score
-                    // For simplicity, we'll show current batting score
-                    if batting_team == info_hash.get("team_a_name").cloned().unwrap_or_default() {
-                        (format!("{runs}/{wickets}"), "-".to_string())
-                    } else {
-                        ("-".to_string(), format!("{runs}/{wickets}"))
-                    }
-                };
-
-                matches.push(MatchSummary {
-                    match_id,
-                    team_a,
-                    team_b,
-                    team_a_score,
-                    team_b_score,
-                    overs,
-                    status: score_hash.get("match_status").cloned().unwrap_or_default(),
-                    stage: info_hash.get("stage").cloned(),
-                });
+    /// Get the list summary for a single match, whether live or not
+    pub async fn get_match_summary(&self, match_id: &str) -> Result<Option<MatchSummary>> {
+        timed("get_match_summary", async {
+            let mut conn = self.conn.clone();
+
+            let score_key = format!("match:{match_id}:score");
+            let score_hash: HashMap<String, String> = conn
+                .hgetall(&score_key)
+                .await
+                .map_err(command("Failed to get match score"))?;
+
+            if score_hash.is_empty() {
+                return Ok(None);
             }
-            debug!("Found {} live matches", matches.len());
-            Ok(matches)
+
+            let info_key = format!("match:{match_id}:info");
+            let info_hash: HashMap<String, String> = conn
+                .hgetall(&info_key)
+                .await
+                .map_err(command("Failed to get match info"))?;
+
+            let mut summary =
+                MatchSummary::from_redis_hashes(match_id.to_string(), score_hash, info_hash);
+            // Best effort, as for the live list: a bad count shouldn't fail reads
+            summary.viewers = read_viewers(&mut conn, match_id).await.unwrap_or_default();
+            Ok(Some(summary))
         })
         .await
     }
 
-    /// Get full match state (info + score + scorecards)
-    pub async fn get_full_match_state(&self, match_id: &str) -> Result<FullMatchState> {
-        let mut conn = self.conn.clone();
-
-        // Get match info
-        let info_key = format!("match:{match_id}:info");
-        let info_hash: HashMap<String, String> = conn
-            .hgetall(&info_key)
-            .await
-            .context("Failed to get match info")?;
+    /// Check whether a match exists (has info in Redis)
+    pub async fn match_exists(&self, match_id: &str) -> Result<bool> {
+        timed("match_exists", async {
+            let mut conn = self.conn.clone();
+            let info_key = format!("match:{match_id}:info");
+            conn.exists(&info_key)
+                .await
+                .map_err(command("Failed to check match info"))
+        })
+        .await
+    }
 
-        if info_hash.is_empty() {
-            anyhow::bail!("Match not found: {match_id}");
-        }
+    /// Publish a message on a pub/sub channel
+    pub async fn publish(&self, channel: &str, payload: &str) -> Result<()> {
+        timed("publish", async {
+            let mut conn = self.conn.clone();
+            conn.publish(channel, payload)
+                .await
+                .map_err(command("Failed to publish"))
+        })
+        .await
+    }
 
-        let info = MatchInfo::from_redis_hash(info_hash)?;
+    /// Append an entry to a stream
+    pub async fn xadd(&self, key: &str, fields: &[(&str, &str)]) -> Result<()> {
+        timed("xadd", async {
+            let mut conn = self.conn.clone();
+            conn.xadd::<_, _, _, _, String>(key, "*", fields)
+                .await
+                .map(|_| ())
+                .map_err(command("Failed to add stream entry"))
+        })
+        .await
+    }
 
-        // Get live score
-        let score_key = format!("match:{match_id}:score");
-        let score_hash: HashMap<String, String> = conn
-            .hgetall(&score_key)
-            .await
-            .context("Failed to get match score")?;
+    /// Acknowledge stream entries for a consumer group
+    pub async fn xack(&self, key: &str, group: &str, ids: &[String]) -> Result<()> {
+        timed("xack", async {
+            let mut conn = self.conn.clone();
+            conn.xack::<_, _, _, ()>(key, group, ids)
+                .await
+                .map_err(command("Failed to acknowledge stream entries"))
+        })
+        .await
+    }
 
-        let score = LiveScore::from_redis_hash(score_hash)?;
+    /// Get the tournament a match belongs to, if recorded
+    pub async fn get_match_tournament(&self, match_id: &str) -> Result<Option<String>> {
+        timed("get_match_tournament", async {
+            let mut conn = self.conn.clone();
+            let info_key = format!("match:{match_id}:info");
+            conn.hget(&info_key, "tournament_id")
+                .await
+                .map_err(command("Failed to get match tournament"))
+        })
+        .await
+    }
 
-        // Get scorecards
-        let scorecard_1_key = format!("match:{match_id}:scorecard:1");
-        let scorecard_1_hash: HashMap<String, String> =
-            conn.hgetall(&scorecard_1_key).await.unwrap_or_default();
+    /// Get full match state (info + score + scorecards)
+    pub async fn get_full_match_state(&self, match_id: &str) -> Result<FullMatchState> {
+        timed("get_full_match_state", async {
+            let mut conn = self.conn.clone();
 
-        let scorecard_inn_1 = if !scorecard_1_hash.is_empty() {
-            Some(Scorecard::from_redis_hash(scorecard_1_hash)?)
-        } else {
-            None
-        };
+            // Get match info
+            let info_key = format!("match:{match_id}:info");
+            let info_hash: HashMap<String, String> = conn
+                .hgetall(&info_key)
+                .await
+                .map_err(command("Failed to get match info"))?;
 
-        let scorecard_2_key = format!("match:{match_id}:scorecard:2");
-        let scorecard_2_hash: HashMap<String, String> =
-            conn.hgetall(&scorecard_2_key).await.unwrap_or_default();
+            if info_hash.is_empty() {
+                return Err(RedisError::MatchNotFound(match_id.to_string()));
+            }
 
-        let scorecard_inn_2 = if !scorecard_2_hash.is_empty() {
-            Some(Scorecard::from_redis_hash(scorecard_2_hash)?)
-        } else {
-            None
-        };
+            let info = MatchInfo::from_redis_hash(info_hash).map_err(RedisError::invalid_data)?;
 
-        Ok(FullMatchState {
-            match_id: match_id.to_string(),
-            info,
-            score,
-            scorecard_inn_1,
-            scorecard_inn_2,
+            // Get live score
+            let score_key = format!("match:{match_id}:score");
+            let score_hash: HashMap<String, String> = conn
+                .hgetall(&score_key)
+                .await
+                .map_err(command("Failed to get match score"))?;
+
+            let score = LiveScore::from_redis_hash(score_hash).map_err(RedisError::invalid_data)?;
+
+            // Get scorecards
+            let scorecard_1_key = format!("match:{match_id}:scorecard:1");
+            let scorecard_1_hash: HashMap<String, String> =
+                conn.hgetall(&scorecard_1_key).await.unwrap_or_default();
+
+            let scorecard_inn_1 = if !scorecard_1_hash.is_empty() {
+                Some(
+                    Scorecard::from_redis_hash(scorecard_1_hash)
+                        .map_err(RedisError::invalid_data)?,
+                )
+            } else {
+                None
+            };
+
+            let scorecard_2_key = format!("match:{match_id}:scorecard:2");
+            let scorecard_2_hash: HashMap<String, String> =
+                conn.hgetall(&scorecard_2_key).await.unwrap_or_default();
+
+            let scorecard_inn_2 = if !scorecard_2_hash.is_empty() {
+                Some(
+                    Scorecard::from_redis_hash(scorecard_2_hash)
+                        .map_err(RedisError::invalid_data)?,
+                )
+            } else {
+                None
+            };
+
+            let viewers = read_viewers(&mut conn, match_id).await.unwrap_or_default();
+
+            Ok(FullMatchState {
+                match_id: match_id.to_string(),
+                info,
+                score,
+                scorecard_inn_1,
+                scorecard_inn_2,
+                viewers,
+            })
         })
+        .await
     }
 
     /// Get only the live score for a match
     pub async fn get_live_score(&self, match_id: &str) -> Result<LiveScore> {
-        let mut conn = self.conn.clone();
-        let score_key = format!("match:{match_id}:score");
-        let score_hash: HashMap<String, String> = conn
-            .hgetall(&score_key)
-            .await
-            .context("Failed to get match score")?;
+        timed("get_live_score", async {
+            let mut conn = self.conn.clone();
+            let score_key = format!("match:{match_id}:score");
+            let score_hash: HashMap<String, String> = conn
+                .hgetall(&score_key)
+                .await
+                .map_err(command("Failed to get match score"))?;
 
-        LiveScore::from_redis_hash(score_hash)
+            LiveScore::from_redis_hash(score_hash).map_err(RedisError::invalid_data)
+        })
+        .await
     }
 
     /// Get scorecard for a specific inning
     pub async fn get_scorecard(&self, match_id: &str, inning: u8) -> Result<Option<Scorecard>> {
-        let mut conn = self.conn.clone();
-        let scorecard_key = format!("match:{match_id}:scorecard:{inning}");
-        let scorecard_hash: HashMap<String, String> =
-            conn.hgetall(&scorecard_key).await.unwrap_or_default();
-
-        if scorecard_hash.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(Scorecard::from_redis_hash(scorecard_hash)?))
-        }
+        timed("get_scorecard", async {
+            let mut conn = self.conn.clone();
+            let scorecard_key = format!("match:{match_id}:scorecard:{inning}");
+            let scorecard_hash: HashMap<String, String> =
+                conn.hgetall(&scorecard_key).await.unwrap_or_default();
+
+            if scorecard_hash.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(
+                    Scorecard::from_redis_hash(scorecard_hash).map_err(RedisError::invalid_data)?,
+                ))
+            }
+        })
+        .await
+    }
+
+    /// Total viewers of a match across the cluster
+    pub async fn get_viewers(&self, match_id: &str) -> Result<u64> {
+        timed("get_viewers", async {
+            let mut conn = self.conn.clone();
+            read_viewers(&mut conn, match_id).await
+        })
+        .await
+    }
+
+    /// Record this node's viewers per match, valid until `expires_at`
+    /// (Unix millis); `gone` are matches it no longer has viewers for
+    pub async fn report_viewers(
+        &self,
+        node_id: &str,
+        counts: &HashMap<String, u64>,
+        gone: &[String],
+        expires_at: u64,
+        ttl: Duration,
+    ) -> Result<()> {
+        timed("report_viewers", async {
+            let mut conn = self.conn.clone();
+            let mut pipe = redis::pipe();
+            for (match_id, count) in counts {
+                let key = viewers_key(match_id);
+                pipe.hset(&key, node_id, format!("{count}:{expires_at}"))
+                    .ignore()
+                    .pexpire(&key, ttl.as_millis() as i64)
+                    .ignore();
+            }
+            for match_id in gone {
+                pipe.hdel(viewers_key(match_id), node_id).ignore();
+            }
+            pipe.query_async::<()>(&mut conn)
+                .await
+                .map_err(command("Failed to report viewers"))
+        })
+        .await
+    }
+
+    /// Register or refresh a node; it drops out after `ttl` without a refresh
+    pub async fn register_node(&self, node: &NodeInfo, ttl: Duration) -> Result<()> {
+        timed("register_node", async {
+            let mut conn = self.conn.clone();
+            let json =
+                serde_json::to_string(node).map_err(|e| RedisError::InvalidData(e.to_string()))?;
+            redis::pipe()
+                .pset_ex(node_key(&node.node_id), json, ttl.as_millis() as u64)
+                .ignore()
+                .sadd(NODES_KEY, &node.node_id)
+                .ignore()
+                .query_async::<()>(&mut conn)
+                .await
+                .map_err(command("Failed to register node"))
+        })
+        .await
+    }
+
+    /// Remove a node's registration and its viewer counts
+    pub async fn deregister_node(&self, node_id: &str, matches: &[String]) -> Result<()> {
+        timed("deregister_node", async {
+            let mut conn = self.conn.clone();
+            let mut pipe = redis::pipe();
+            pipe.del(node_key(node_id)).ignore();
+            pipe.srem(NODES_KEY, node_id).ignore();
+            for match_id in matches {
+                pipe.hdel(viewers_key(match_id), node_id).ignore();
+            }
+            pipe.query_async::<()>(&mut conn)
+                .await
+                .map_err(command("Failed to deregister node"))
+        })
+        .await
+    }
+
+    /// Get every registered node, pruning IDs of nodes that stopped
+    /// without deregistering
+    pub async fn get_nodes(&self) -> Result<Vec<NodeInfo>> {
+        timed("get_nodes", async {
+            let mut conn = self.conn.clone();
+            let node_ids: Vec<String> = conn
+                .smembers(NODES_KEY)
+                .await
+                .map_err(command("Failed to get node IDs"))?;
+            if node_ids.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let keys: Vec<String> = node_ids.iter().map(|id| node_key(id)).collect();
+            let values: Vec<Option<String>> = conn
+                .mget(&keys)
+                .await
+                .map_err(command("Failed to get nodes"))?;
+
+            let mut expired = Vec::new();
+            let mut nodes = Vec::new();
+            for (node_id, value) in node_ids.into_iter().zip(values) {
+                match value {
+                    Some(json) => nodes.extend(serde_json::from_str::<NodeInfo>(&json).ok()),
+                    None => expired.push(node_id),
+                }
+            }
+            if !expired.is_empty() {
+                let _: redis::RedisResult<()> = conn.srem(NODES_KEY, &expired).await;
+            }
+            nodes.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+            Ok(nodes)
+        })
+        .await
     }
 
     /// Execute an operation with retry logic
-    async fn with_retry<F, Fut, T>(&self, mut operation: F) -> Result<T>
+    async fn with_retry<F, Fut, T>(&self, name: &'static str, mut operation: F) -> Result<T>
     where
         F: FnMut() -> Fut,
-        Fut: std::future::Future<Output = Result<T>>,
+        Fut: Future<Output = Result<T>>,
     {
-        const MAX_RETRIES: u32 = 3;
-        const RETRY_DELAY_MS: u64 = 100;
-
-        let mut last_error = None;
+        let max_retries = self.retries;
 
-        for attempt in 1..=MAX_RETRIES {
+        for attempt in 1..=max_retries {
             match operation().await {
                 Ok(result) => return Ok(result),
                 Err(e) => {
-                    if attempt < MAX_RETRIES {
+                    if attempt < max_retries {
+                        metrics::REDIS_RETRIES.with_label_values(&[name]).inc();
                         tracing::warn!(
                             "Redis operation failed (attempt {}/{}): {}. Retrying in {}ms...",
                             attempt,
-                            MAX_RETRIES,
+                            max_retries,
                             e,
-                            RETRY_DELAY_MS
+                            self.retry_delay.as_millis()
                         );
-                        tokio::time::sleep(tokio::time::Duration::from_millis(RETRY_DELAY_MS))
-                            .await;
-                        last_error = Some(e);
+                        tokio::time::sleep(self.retry_delay).await;
                     } else {
                         tracing::error!(
                             "Redis operation failed after {} attempts: {}",
-                            MAX_RETRIES,
+                            max_retries,
                             e
                         );
-                        last_error = Some(e);
+                        return Err(e);
                     }
                 }
             }
         }
 
-        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Operation failed with no error")))
+        unreachable!()
     }
 }
@@ -0,0 +1,168 @@
+use crate::auth::Grant;
+use crate::metrics;
+use crate::models::ServerMessage;
+use crate::websocket::{MatchEvent, WsState};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+    Extension, Json,
+};
+use futures::stream::{self, Stream, StreamExt};
+use serde_json::json;
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use tracing::{debug, error, info};
+
+/// Handler for GET /api/matches/:match_id/events
+///
+/// Streams the same messages as the WebSocket route as Server-Sent Events.
+/// Event IDs are `{boot_id}-{seq}`; a client reconnecting with
+/// `Last-Event-ID` gets the events it missed, or a fresh `full_state` if
+/// they are no longer available.
+pub async fn match_events(
+    Path(match_id): Path<String>,
+    Extension(grant): Extension<Grant>,
+    headers: HeaderMap,
+    State(state): State<WsState>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, Json<serde_json::Value>)>
+{
+    let last_seq = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|id| state.parse_event_id(id));
+
+    let channel = state.open_match_channel(&grant, &match_id).await?;
+
+    // Try to replay what the client missed
+    let (rx, latest_seq, missed) = match last_seq {
+        Some(seq) => channel.subscribe_after(seq),
+        None => {
+            let (rx, latest_seq) = channel.subscribe();
+            (rx, latest_seq, None)
+        }
+    };
+
+    let initial = match missed {
+        Some(events) => {
+            debug!(
+                "Resuming SSE stream for match {} with {} missed events",
+                match_id,
+                events.len()
+            );
+            events
+                .iter()
+                .map(|event| to_sse_event(&state, event))
+                .collect()
+        }
+        None => {
+            // Read after subscribing, so the full state is at least as new
+            // as the event ID it is labelled with
+            match state.redis.get_full_match_state(&match_id).await {
+                Ok(full_state) => {
+                    let event = MatchEvent::new(
+                        latest_seq,
+                        ServerMessage::FullState {
+                            data: Box::new(full_state),
+                        },
+                    );
+                    vec![to_sse_event(&state, &event)]
+                }
+                Err(e) => {
+                    error!("Failed to get match state: {}", e);
+                    return Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        Json(json!({
+                            "error": "Internal server error",
+                            "message": "Failed to fetch match state"
+                        })),
+                    ));
+                }
+            }
+        }
+    };
+
+    info!("SSE client subscribed to match: {}", match_id);
+
+    // Ends after a `shutdown` event when the server starts shutting down
+    let updates = stream::unfold(
+        (rx, channel, state, match_id, false),
+        |(mut rx, channel, state, match_id, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                let received = tokio::select! {
+                    received = rx.recv() => received,
+                    _ = state.shutting_down() => {
+                        let sse_event = shutdown_event(&state);
+                        return Some((sse_event, (rx, channel, state, match_id, true)));
+                    }
+                };
+                match received {
+                    Ok(event) => {
+                        let sse_event = to_sse_event(&state, &event);
+                        return Some((sse_event, (rx, channel, state, match_id, false)));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // Fell behind: send a fresh full state instead of the gap
+                        debug!(
+                            "SSE client lagged by {} events for match {}",
+                            skipped, match_id
+                        );
+                        metrics::BROADCAST_DROPPED
+                            .with_label_values(&["lagged"])
+                            .inc_by(skipped);
+                        // Labelled like the initial full state, so a reconnect
+                        // resumes after it rather than replaying older events
+                        let latest_seq = channel.latest_seq();
+                        if let Ok(full_state) = state.redis.get_full_match_state(&match_id).await {
+                            let event = MatchEvent::new(
+                                latest_seq,
+                                ServerMessage::FullState {
+                                    data: Box::new(full_state),
+                                },
+                            );
+                            let sse_event = to_sse_event(&state, &event);
+                            return Some((sse_event, (rx, channel, state, match_id, false)));
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+
+    let events = stream::iter(initial).chain(updates).map(Ok);
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Build the last event sent before shutdown; `retry` makes `EventSource`
+/// wait that long before reconnecting
+fn shutdown_event(state: &WsState) -> Event {
+    let reconnect_after = state.reconnect_hint();
+    let message = ServerMessage::Shutdown {
+        reconnect_after_ms: reconnect_after.as_millis() as u64,
+    };
+    Event::default()
+        .retry(reconnect_after)
+        .data(to_json(&message))
+}
+
+/// Build an SSE event carrying a match event
+fn to_sse_event(state: &WsState, event: &MatchEvent) -> Event {
+    let data = event.json().unwrap_or_else(|e| {
+        error!("Failed to serialize SSE message: {}", e);
+        "{}"
+    });
+    Event::default().id(state.event_id(event.seq)).data(data)
+}
+
+/// Serialize a message the same way the WebSocket route does
+fn to_json(message: &ServerMessage) -> String {
+    serde_json::to_string(message).unwrap_or_else(|e| {
+        error!("Failed to serialize SSE message: {}", e);
+        String::from("{}")
+    })
+}
@@ -0,0 +1,270 @@
+use crate::coalesce::Coalescer;
+use crate::health::{ListenerState, ListenerStatus};
+use crate::metrics;
+use crate::pubsub::handle_update;
+use crate::redis_client::RedisClient;
+use anyhow::{Context, Result};
+use redis::aio::MultiplexedConnection;
+use redis::streams::{StreamReadOptions, StreamReadReply};
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// How often per-match streams are rediscovered
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Set once the first listener in this process has found its streams;
+/// later starts are reconnects, which must not skip entries with `$`
+static STREAMS_DISCOVERED: AtomicBool = AtomicBool::new(false);
+
+/// Which streams carry match updates
+#[derive(Debug, Clone)]
+pub enum StreamLayout {
+    /// One stream for all matches; entries carry a `match_id` field
+    Global(String),
+    /// One stream per match at match:{match_id}:updates
+    PerMatch,
+}
+
+/// Settings for Redis Streams ingestion
+#[derive(Debug, Clone)]
+pub struct StreamSettings {
+    pub layout: StreamLayout,
+    /// Consumer group; every replica needs its own so each sees all updates,
+    /// with a name that survives restarts
+    pub group: String,
+    pub consumer: String,
+    pub block_ms: usize,
+    pub batch_size: usize,
+}
+
+/// A stream entry to acknowledge once its update has been applied
+#[derive(Debug, Clone)]
+pub struct EntryId {
+    pub key: String,
+    pub id: String,
+}
+
+/// Acknowledges entries for the listener's consumer group
+#[derive(Clone)]
+pub struct StreamAcker {
+    redis: RedisClient,
+    group: String,
+}
+
+impl StreamAcker {
+    pub fn new(redis: RedisClient, group: String) -> Self {
+        Self { redis, group }
+    }
+
+    /// Acknowledge entries; any that fail stay pending and are replayed
+    /// when the listener next starts
+    pub async fn ack(&self, entries: Vec<EntryId>) {
+        let mut by_key: HashMap<String, Vec<String>> = HashMap::new();
+        for entry in entries {
+            by_key.entry(entry.key).or_default().push(entry.id);
+        }
+        for (key, ids) in by_key {
+            if let Err(e) = self.redis.xack(&key, &self.group, &ids).await {
+                warn!(
+                    "Failed to acknowledge {} entries on {}: {}",
+                    ids.len(),
+                    key,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Start the Redis Streams listener
+///
+/// Entries delivered to this consumer but never acknowledged (e.g. the
+/// process stopped before broadcasting them) are replayed first, then new
+/// entries are read from where the group left off, so nothing published
+/// while disconnected is lost. Entries are acknowledged once their update
+/// has been applied, so a replayed one may be broadcast twice.
+pub async fn start_stream_listener(
+    redis_url: &str,
+    coalescer: Coalescer,
+    settings: &StreamSettings,
+    status: &ListenerStatus,
+) -> Result<()> {
+    let client = redis::Client::open(redis_url).context("Failed to create Redis client")?;
+
+    // Dedicated connection: XREADGROUP BLOCK would stall shared connections
+    let mut conn = client
+        .get_multiplexed_async_connection()
+        .await
+        .context("Failed to connect for stream reads")?;
+
+    let new_group_start = if STREAMS_DISCOVERED.load(Ordering::Relaxed) {
+        "0"
+    } else {
+        "$"
+    };
+    let mut keys = discover_streams(&mut conn, settings, new_group_start).await?;
+    STREAMS_DISCOVERED.store(true, Ordering::Relaxed);
+    let mut last_discovery = Instant::now();
+
+    info!(
+        "Redis Streams listener started (group: {}, consumer: {}, streams: {})",
+        settings.group,
+        settings.consumer,
+        keys.len()
+    );
+    status.set_state(ListenerState::Connected);
+
+    // Last sequence number seen per match, used to drop stale payloads
+    let mut last_seq: HashMap<String, u64> = HashMap::new();
+
+    // Start with our pending entries ("0"), then switch to new ones (">")
+    let mut replaying = true;
+    // Pending entries stay pending until applied, so the replay carries on
+    // after the last one read on each stream rather than from "0" again
+    let mut replayed: HashMap<String, String> = HashMap::new();
+
+    loop {
+        if matches!(settings.layout, StreamLayout::PerMatch)
+            && last_discovery.elapsed() >= DISCOVERY_INTERVAL
+        {
+            // Streams that appear later may already hold entries for us
+            keys = discover_streams(&mut conn, settings, "0").await?;
+            last_discovery = Instant::now();
+        }
+
+        if keys.is_empty() {
+            tokio::time::sleep(Duration::from_millis(settings.block_ms as u64)).await;
+            continue;
+        }
+
+        let ids: Vec<&str> = keys
+            .iter()
+            .map(|key| {
+                if replaying {
+                    replayed.get(key).map_or("0", String::as_str)
+                } else {
+                    ">"
+                }
+            })
+            .collect();
+        let options = StreamReadOptions::default()
+            .group(&settings.group, &settings.consumer)
+            .count(settings.batch_size)
+            .block(settings.block_ms);
+
+        let reply: Option<StreamReadReply> = conn
+            .xread_options(&keys, &ids, &options)
+            .await
+            .context("Failed to read from streams")?;
+
+        let mut received = 0;
+        for stream in reply.map(|r| r.keys).unwrap_or_default() {
+            for entry in stream.ids {
+                received += 1;
+                if replaying {
+                    replayed.insert(stream.key.clone(), entry.id.clone());
+                }
+
+                match entry_match_id(&stream.key, &entry.map, &settings.layout) {
+                    Some(match_id) => {
+                        let payload: Vec<u8> = entry.get("payload").unwrap_or_default();
+                        debug!("Received stream entry {} for match: {}", entry.id, match_id);
+                        metrics::UPDATES_RECEIVED
+                            .with_label_values(&["streams"])
+                            .inc();
+                        status.record_message();
+                        let ack = EntryId {
+                            key: stream.key.clone(),
+                            id: entry.id.clone(),
+                        };
+                        handle_update(&coalescer, &match_id, &payload, &mut last_seq, vec![ack])
+                            .await;
+                    }
+                    None => {
+                        warn!(
+                            "Stream entry {} in {} has no match_id, skipping",
+                            entry.id, stream.key
+                        );
+                        // Acknowledge it anyway so it doesn't stay pending forever
+                        let _: () = conn
+                            .xack(&stream.key, &settings.group, &[&entry.id])
+                            .await
+                            .context("Failed to acknowledge stream entry")?;
+                    }
+                }
+            }
+        }
+
+        if replaying && received == 0 {
+            debug!("Finished replaying pending stream entries");
+            replaying = false;
+        }
+    }
+}
+
+/// List the streams to read and make sure the consumer group exists on each
+///
+/// Groups that don't exist yet start at `new_group_start`: `$` when the
+/// process first starts rather than replaying history, `0` for streams
+/// found while running or after a reconnect.
+async fn discover_streams(
+    conn: &mut MultiplexedConnection,
+    settings: &StreamSettings,
+    new_group_start: &str,
+) -> Result<Vec<String>> {
+    let keys = match &settings.layout {
+        StreamLayout::Global(key) => vec![key.clone()],
+        StreamLayout::PerMatch => {
+            let mut iter: redis::AsyncIter<String> = conn
+                .scan_match("match:*:updates")
+                .await
+                .context("Failed to scan for match streams")?;
+            let mut keys = Vec::new();
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            keys
+        }
+    };
+
+    for key in &keys {
+        let result: redis::RedisResult<()> = conn
+            .xgroup_create_mkstream(key, &settings.group, new_group_start)
+            .await;
+        match result {
+            Ok(()) => info!("Created consumer group {} on {}", settings.group, key),
+            Err(e) if e.code() == Some("BUSYGROUP") => {}
+            Err(e) => {
+                error!("Failed to create consumer group on {}: {}", key, e);
+                return Err(e).context("Failed to create consumer group");
+            }
+        }
+    }
+
+    debug!("Reading from {} stream(s)", keys.len());
+    Ok(keys)
+}
+
+/// Work out which match a stream entry belongs to
+fn entry_match_id(
+    key: &str,
+    fields: &HashMap<String, redis::Value>,
+    layout: &StreamLayout,
+) -> Option<String> {
+    match layout {
+        StreamLayout::Global(_) => fields
+            .get("match_id")
+            .and_then(|v| redis::from_redis_value(v).ok()),
+        StreamLayout::PerMatch => {
+            // Extract match_id from key (match:{match_id}:updates)
+            let parts: Vec<&str> = key.split(':').collect();
+            if parts.len() != 3 {
+                return None;
+            }
+            Some(parts[1].to_string())
+        }
+    }
+}
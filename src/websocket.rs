@@ -1,81 +1,746 @@
-use crate::models::{ClientMessage, ServerMessage};
-use crate::redis_client::RedisClient;
+use crate::auth::Grant;
+use crate::cors::AllowedOrigins;
+use crate::encoding::{Encoding, FrameCache};
+use crate::metrics::{self, ConnectionGauge};
+use crate::models::{
+    unix_millis, ClientMessage, ErrorCode, Feature, ListChange, MatchSummary, ServerMessage, Topic,
+    PROTOCOL_VERSION,
+};
+use crate::outbox::{Outbox, Outgoing, SlowClientPolicy};
+use crate::ratelimit::{ClientIp, RateLimiter};
+use crate::redis_client::{RedisClient, RedisError};
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Extension, Query, State, WebSocketUpgrade,
     },
-    response::Response,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
 };
 use futures::{
     future::FutureExt,
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
-use tracing::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+/// Number of recent events kept per match for resuming streams
+const HISTORY_SIZE: usize = 100;
+
+/// How often channels without subscribers are looked for; one idle for a
+/// whole interval is dropped
+const CHANNEL_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message broadcast to subscribers of a match
+///
+/// Cheap to clone: every subscriber shares the message and its encodings.
+#[derive(Debug, Clone)]
+pub struct MatchEvent {
+    /// Position in the match's event sequence (starts at 1)
+    pub seq: u64,
+    pub message: Arc<ServerMessage>,
+    frames: Arc<FrameCache>,
+    /// When it was created, for fan-out latency
+    created: Instant,
+}
+
+impl MatchEvent {
+    pub fn new(seq: u64, message: ServerMessage) -> Self {
+        Self {
+            seq,
+            message: Arc::new(message),
+            frames: Arc::new(FrameCache::default()),
+            created: Instant::now(),
+        }
+    }
+
+    /// JSON text of the message, serialized once for all subscribers
+    pub fn json(&self) -> anyhow::Result<&str> {
+        self.frames.json(&self.message)
+    }
+
+    /// WebSocket frame in the given encoding, serialized once for all subscribers
+    pub fn frame(&self, encoding: Encoding) -> anyhow::Result<Message> {
+        self.frames.frame(encoding, &self.message)
+    }
+}
+
+/// Sequence counter and recent history for a match channel
+struct EventLog {
+    next_seq: u64,
+    recent: VecDeque<MatchEvent>,
+}
+
+/// Broadcast channel for one match, remembering recent events
+#[derive(Clone)]
+pub struct MatchChannel {
+    pub sender: broadcast::Sender<MatchEvent>,
+    log: Arc<Mutex<EventLog>>,
+    /// Had no subscribers at the last prune
+    idle: Arc<AtomicBool>,
+}
+
+impl MatchChannel {
+    fn new(capacity: usize, first_seq: u64) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            sender,
+            log: Arc::new(Mutex::new(EventLog {
+                next_seq: first_seq,
+                recent: VecDeque::with_capacity(HISTORY_SIZE),
+            })),
+            idle: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Subscribe, returning the sequence number of the latest event so far
+    pub fn subscribe(&self) -> (broadcast::Receiver<MatchEvent>, u64) {
+        let log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        (self.sender.subscribe(), log.next_seq - 1)
+    }
+
+    /// Sequence number of the latest event sent
+    pub fn latest_seq(&self) -> u64 {
+        self.log.lock().unwrap_or_else(|e| e.into_inner()).next_seq - 1
+    }
+
+    /// Like `subscribe`, also returning the events after `after_seq` that
+    /// were already sent, or None if some of them are no longer in history
+    pub fn subscribe_after(
+        &self,
+        after_seq: u64,
+    ) -> (
+        broadcast::Receiver<MatchEvent>,
+        u64,
+        Option<Vec<MatchEvent>>,
+    ) {
+        // Holding the log while subscribing means nothing is missed or repeated
+        let log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        let rx = self.sender.subscribe();
+
+        let oldest = log.recent.front().map_or(log.next_seq, |event| event.seq);
+        let missed = if after_seq >= log.next_seq || after_seq + 1 < oldest {
+            None
+        } else {
+            Some(
+                log.recent
+                    .iter()
+                    .filter(|event| event.seq > after_seq)
+                    .cloned()
+                    .collect(),
+            )
+        };
+
+        (rx, log.next_seq - 1, missed)
+    }
+
+    /// Record and send a message
+    fn send(&self, message: ServerMessage) {
+        let mut log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        let event = MatchEvent::new(log.next_seq, message);
+        log.next_seq += 1;
+
+        if log.recent.len() == HISTORY_SIZE {
+            log.recent.pop_front();
+        }
+        log.recent.push_back(event.clone());
+
+        // Ignore errors if no receivers
+        if let Ok(receivers) = self.sender.send(event) {
+            metrics::BROADCAST_SENT.inc_by(receivers as u64);
+        }
+    }
+
+    /// Send a message that only matters live, without recording it for
+    /// replay or using up a sequence number
+    fn send_transient(&self, message: ServerMessage) {
+        let event = MatchEvent::new(self.latest_seq(), message);
+        if let Ok(receivers) = self.sender.send(event) {
+            metrics::BROADCAST_SENT.inc_by(receivers as u64);
+        }
+    }
+}
+
+/// Per-connection WebSocket settings
+#[derive(Debug, Clone)]
+pub struct WsConfig {
+    /// How often the server pings and sends a `heartbeat` message
+    pub ping_interval: Duration,
+    /// Close connections that send nothing (not even a pong) for this long
+    pub idle_timeout: Duration,
+    /// Broadcasts queued per connection before the slow client policy kicks in
+    pub send_queue_size: usize,
+    pub slow_client_policy: SlowClientPolicy,
+    /// Open connections allowed per client IP (0 for no limit)
+    pub max_connections_per_ip: usize,
+    /// Matches one connection may subscribe to at once (0 for no limit)
+    pub max_subscriptions: usize,
+    /// Browser origins allowed to connect
+    pub allowed_origins: AllowedOrigins,
+    /// Inbound messages allowed per second per client (0 for no limit)
+    pub message_rate: f64,
+    /// Inbound messages a client may send in a burst
+    pub message_burst: u32,
+    /// Events buffered per broadcast channel before receivers lag
+    pub broadcast_capacity: usize,
+    /// Clients told to reconnect at shutdown are spread over this long
+    pub reconnect_spread: Duration,
+}
 
 /// Shared state for WebSocket connections
 #[derive(Clone)]
 pub struct WsState {
     /// Broadcast channels for each match_id
-    /// match_id -> broadcast sender
-    pub channels: Arc<RwLock<HashMap<String, broadcast::Sender<ServerMessage>>>>,
+    /// match_id -> channel
+    pub channels: Arc<RwLock<HashMap<String, MatchChannel>>>,
+    /// Identifies this process run; event sequences restart with it
+    pub boot_id: u64,
+    /// Broadcast channel for live matches list changes
+    pub list_channel: broadcast::Sender<ServerMessage>,
+    /// Last summary sent to list subscribers for each live match
+    /// match_id -> summary
+    pub live_summaries: Arc<RwLock<HashMap<String, MatchSummary>>>,
+    /// First sequence number for new channels, kept above every dropped
+    /// channel's so old event IDs never resume into a recreated one
+    next_channel_seq: Arc<AtomicU64>,
+    /// Open WebSocket connections per client IP
+    connections_per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    /// ID for the next WebSocket connection
+    next_connection_id: Arc<AtomicU64>,
+    /// Open WebSocket connections by ID, for admin tools
+    connections: Arc<RwLock<HashMap<u64, Arc<Connection>>>>,
+    /// Set once the server starts shutting down
+    shutdown: Arc<watch::Sender<bool>>,
+    /// Reconnect hints handed out so far
+    reconnect_hints: Arc<AtomicU64>,
+    /// Inbound message limits, one bucket per connection
+    message_limiter: RateLimiter,
     /// Redis client for fetching data
     pub redis: RedisClient,
+    pub config: WsConfig,
 }
 
 impl WsState {
-    pub fn new(redis: RedisClient) -> Self {
+    pub fn new(redis: RedisClient, config: WsConfig) -> Self {
+        let (list_channel, _) = broadcast::channel(config.broadcast_capacity);
         Self {
             channels: Arc::new(RwLock::new(HashMap::new())),
+            boot_id: unix_millis(),
+            list_channel,
+            live_summaries: Arc::new(RwLock::new(HashMap::new())),
+            next_channel_seq: Arc::new(AtomicU64::new(1)),
+            connections_per_ip: Arc::new(Mutex::new(HashMap::new())),
+            next_connection_id: Arc::new(AtomicU64::new(1)),
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            shutdown: Arc::new(watch::Sender::new(false)),
+            reconnect_hints: Arc::new(AtomicU64::new(0)),
+            message_limiter: RateLimiter::new(config.message_rate, config.message_burst),
             redis,
+            config,
+        }
+    }
+
+    /// Format an event sequence number as an ID clients can hand back
+    pub fn event_id(&self, seq: u64) -> String {
+        format!("{}-{seq}", self.boot_id)
+    }
+
+    /// Parse an ID from `event_id`, ignoring IDs from a previous process run
+    pub fn parse_event_id(&self, id: &str) -> Option<u64> {
+        let (boot, seq) = id.split_once('-')?;
+        if boot.parse::<u64>().ok()? != self.boot_id {
+            return None;
         }
+        seq.parse().ok()
     }
 
     /// Get or create a broadcast channel for a match
-    pub async fn get_or_create_channel(&self, match_id: &str) -> broadcast::Sender<ServerMessage> {
+    ///
+    /// Returns None if the match doesn't exist, so unknown IDs never get a channel.
+    pub async fn get_or_create_channel(
+        &self,
+        match_id: &str,
+    ) -> Result<Option<MatchChannel>, RedisError> {
+        if let Some(channel) = self.channels.read().await.get(match_id) {
+            channel.idle.store(false, Ordering::Relaxed);
+            return Ok(Some(channel.clone()));
+        }
+
+        // Checked without holding the lock; a concurrent creator wins below
+        if !self.redis.match_exists(match_id).await? {
+            return Ok(None);
+        }
+
         let mut channels = self.channels.write().await;
+        let channel = channels.entry(match_id.to_string()).or_insert_with(|| {
+            MatchChannel::new(
+                self.config.broadcast_capacity,
+                self.next_channel_seq.load(Ordering::Relaxed),
+            )
+        });
+        channel.idle.store(false, Ordering::Relaxed);
+        Ok(Some(channel.clone()))
+    }
 
-        if let Some(sender) = channels.get(match_id) {
-            sender.clone()
-        } else {
-            let (tx, _) = broadcast::channel(100);
-            channels.insert(match_id.to_string(), tx.clone());
-            tx
+    /// Drop channels that have had no subscribers for a whole interval,
+    /// until the task is aborted
+    ///
+    /// Waiting an interval leaves time for a caller of
+    /// `get_or_create_channel` to subscribe.
+    pub async fn prune_channels(self) {
+        let mut ticker = tokio::time::interval(CHANNEL_PRUNE_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let mut channels = self.channels.write().await;
+            let before = channels.len();
+            channels.retain(|_, channel| {
+                if channel.sender.receiver_count() > 0 {
+                    channel.idle.store(false, Ordering::Relaxed);
+                    return true;
+                }
+                if !channel.idle.swap(true, Ordering::Relaxed) {
+                    return true;
+                }
+                // Past IDs for this match must look too old to resume from
+                let next_seq = channel.latest_seq() + 1;
+                self.next_channel_seq
+                    .fetch_max(next_seq + 1, Ordering::Relaxed);
+                false
+            });
+            let pruned = before - channels.len();
+            if pruned > 0 {
+                debug!("Dropped {} idle match channels", pruned);
+            }
+        }
+    }
+
+    /// Check access to a match and get its channel, for the SSE and
+    /// long-poll routes; on failure, the response to send instead
+    pub async fn open_match_channel(
+        &self,
+        grant: &Grant,
+        match_id: &str,
+    ) -> Result<MatchChannel, (StatusCode, Json<serde_json::Value>)> {
+        let channel = match self.authorize(grant, match_id).await {
+            Ok(true) => self.get_or_create_channel(match_id).await,
+            Ok(false) => {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    Json(json!({
+                        "error": "Forbidden",
+                        "match_id": match_id
+                    })),
+                ))
+            }
+            Err(e) => Err(e),
+        };
+
+        match channel {
+            Ok(Some(channel)) => Ok(channel),
+            Ok(None) => Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "error": "Match not found",
+                    "match_id": match_id
+                })),
+            )),
+            Err(e) => {
+                error!("Failed to look up match {}: {}", match_id, e);
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({
+                        "error": "Internal server error",
+                        "message": "Failed to look up match"
+                    })),
+                ))
+            }
+        }
+    }
+
+    /// Whether a client may access a match
+    pub async fn authorize(&self, grant: &Grant, match_id: &str) -> Result<bool, RedisError> {
+        if grant.allows(match_id, None) {
+            return Ok(true);
+        }
+        if !grant.restricts_tournaments() {
+            return Ok(false);
         }
+        let tournament_id = self.redis.get_match_tournament(match_id).await?;
+        Ok(grant.allows(match_id, tournament_id.as_deref()))
+    }
+
+    /// Reserve a connection slot for an IP, or None if it is at the limit
+    fn acquire_connection(&self, ip: IpAddr) -> Option<ConnectionSlot> {
+        let mut counts = self
+            .connections_per_ip
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let count = counts.entry(ip).or_insert(0);
+
+        let limit = self.config.max_connections_per_ip;
+        if limit > 0 && *count >= limit {
+            return None;
+        }
+        *count += 1;
+
+        Some(ConnectionSlot {
+            counts: self.connections_per_ip.clone(),
+            ip,
+        })
     }
 
     /// Broadcast a message to all subscribers of a match
     pub async fn broadcast(&self, match_id: &str, message: ServerMessage) {
         let channels = self.channels.read().await;
 
-        if let Some(sender) = channels.get(match_id) {
-            // Ignore errors if no receivers
-            let _ = sender.send(message);
+        if let Some(channel) = channels.get(match_id) {
+            channel.send(message);
+        }
+    }
+
+    /// Broadcast a message to a match's current subscribers without adding
+    /// it to the replay history
+    pub async fn broadcast_transient(&self, match_id: &str, message: ServerMessage) {
+        if let Some(channel) = self.channels.read().await.get(match_id) {
+            channel.send_transient(message);
+        }
+    }
+
+    /// Start shutting down: WebSockets announce it and close, SSE streams
+    /// and long polls end
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Resolves once shutdown has begun
+    pub async fn shutting_down(&self) {
+        let mut rx = self.shutdown.subscribe();
+        let _ = rx.wait_for(|down| *down).await;
+    }
+
+    /// How long a client should wait before reconnecting after shutdown
+    pub fn reconnect_hint(&self) -> Duration {
+        // Golden ratio steps spread successive clients evenly over the window
+        let n = self.reconnect_hints.fetch_add(1, Ordering::Relaxed);
+        self.config
+            .reconnect_spread
+            .mul_f64((n as f64 * 0.618_033_988_749_895).fract())
+    }
+
+    /// Number of open WebSocket connections
+    pub async fn connection_count(&self) -> usize {
+        self.connections.read().await.len()
+    }
+
+    /// Wait until every WebSocket connection has closed
+    pub async fn drained(&self) {
+        while self.connection_count().await > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Subscriber counts for every match with a channel
+    pub async fn match_stats(&self) -> Vec<MatchStats> {
+        let mut stats: Vec<MatchStats> = self
+            .channels
+            .read()
+            .await
+            .iter()
+            .map(|(match_id, channel)| MatchStats {
+                match_id: match_id.clone(),
+                subscribers: channel.sender.receiver_count(),
+                latest_seq: channel.latest_seq(),
+                cluster_viewers: None,
+            })
+            .collect();
+        stats.sort_by(|a, b| a.match_id.cmp(&b.match_id));
+        stats
+    }
+
+    /// Details of every open WebSocket connection
+    pub async fn connection_infos(&self) -> Vec<ConnectionInfo> {
+        let connections: Vec<Arc<Connection>> =
+            self.connections.read().await.values().cloned().collect();
+        let mut infos = Vec::with_capacity(connections.len());
+        for conn in connections {
+            infos.push(conn.info().await);
+        }
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+
+    /// Details of one open WebSocket connection
+    pub async fn connection_info(&self, id: u64) -> Option<ConnectionInfo> {
+        let conn = self.connections.read().await.get(&id).cloned()?;
+        Some(conn.info().await)
+    }
+
+    /// Close a connection, returning false if it isn't open
+    pub async fn close_connection(&self, id: u64, reason: &'static str) -> bool {
+        let Some(conn) = self.connections.read().await.get(&id).cloned() else {
+            return false;
+        };
+        info!("Closing WebSocket connection {}: {}", id, reason);
+        conn.outbox.close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: reason.into(),
+        }));
+        true
+    }
+
+    /// Subscribe to live matches list changes, returning the current list
+    pub async fn subscribe_list(
+        &self,
+    ) -> Result<(broadcast::Receiver<ServerMessage>, Vec<MatchSummary>), RedisError> {
+        // Subscribe before reading so no change between the two is missed
+        let rx = self.list_channel.subscribe();
+        let matches = self.redis.get_live_matches().await?;
+
+        *self.live_summaries.write().await = matches
+            .iter()
+            .map(|summary| (summary.match_id.clone(), summary.clone()))
+            .collect();
+
+        Ok((rx, matches))
+    }
+
+    /// Re-read a match's summary and tell list subscribers if it changed
+    pub async fn update_list_entry(&self, match_id: &str) {
+        // Nobody is watching the list; it gets rebuilt on the next subscribe
+        if self.list_channel.receiver_count() == 0 {
+            return;
+        }
+
+        let latest = match self.redis.get_match_summary(match_id).await {
+            Ok(latest) => latest,
+            Err(e) => {
+                error!("Failed to get summary for match {}: {}", match_id, e);
+                return;
+            }
+        };
+        let is_live = latest.as_ref().is_some_and(MatchSummary::is_live);
+
+        let mut summaries = self.live_summaries.write().await;
+        let message = match (summaries.get(match_id), latest) {
+            (None, Some(summary)) if is_live => {
+                summaries.insert(match_id.to_string(), summary.clone());
+                ServerMessage::ListUpdate {
+                    change: ListChange::Started,
+                    data: summary,
+                }
+            }
+            (Some(previous), Some(summary)) if is_live && *previous != summary => {
+                summaries.insert(match_id.to_string(), summary.clone());
+                ServerMessage::ListUpdate {
+                    change: ListChange::Updated,
+                    data: summary,
+                }
+            }
+            (Some(_), latest) if !is_live => {
+                let previous = summaries.remove(match_id);
+                // Report the final status if the match still exists
+                let Some(summary) = latest.or(previous) else {
+                    return;
+                };
+                ServerMessage::ListUpdate {
+                    change: ListChange::Ended,
+                    data: summary,
+                }
+            }
+            _ => return,
+        };
+
+        let _ = self.list_channel.send(message);
+    }
+}
+
+/// Subscribers of one match, for admin tools
+#[derive(Debug, Serialize)]
+pub struct MatchStats {
+    pub match_id: String,
+    /// WebSocket, SSE and long-poll receivers
+    pub subscribers: usize,
+    /// Sequence number of the latest broadcast
+    pub latest_seq: u64,
+    /// Viewers on all nodes, if looked up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster_viewers: Option<u64>,
+}
+
+/// One open WebSocket connection, for admin tools
+#[derive(Debug, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub remote_addr: IpAddr,
+    /// Token subject or API key name
+    pub client: String,
+    pub encoding: &'static str,
+    pub protocol_version: u32,
+    pub features: Vec<Feature>,
+    /// Unix time in milliseconds
+    pub connected_at: u64,
+    pub subscriptions: Vec<String>,
+    pub list_subscribed: bool,
+    /// Frame payload bytes written to the socket
+    pub bytes_sent: u64,
+}
+
+/// A counted connection from one IP, released on drop
+struct ConnectionSlot {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
         }
     }
 }
 
+/// Query parameters for the WebSocket route
+#[derive(Debug, Deserialize)]
+pub struct WsParams {
+    /// Frame encoding: json (default), msgpack or cbor
+    pub encoding: Option<String>,
+}
+
 /// WebSocket upgrade handler
-pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<WsState>) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+///
+/// The encoding comes from `?encoding=` if given, otherwise from the
+/// subprotocol the client offered (msgpack, cbor or json).
+///
+/// Browsers don't apply CORS to WebSockets, so the `Origin` header is
+/// checked against the CORS allow-list here. Clients that send no origin
+/// (native apps, servers) aren't affected.
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    Extension(ClientIp(ip)): Extension<ClientIp>,
+    Extension(grant): Extension<Grant>,
+    headers: HeaderMap,
+    Query(params): Query<WsParams>,
+    State(state): State<WsState>,
+) -> Response {
+    if state.is_shutting_down() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server shutting down").into_response();
+    }
+
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        if !state.config.allowed_origins.allows(origin) {
+            warn!("Rejecting WebSocket from {} with origin {:?}", ip, origin);
+            return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+        }
+    }
+
+    let requested = match params.encoding.as_deref() {
+        Some(name) => match Encoding::from_name(name) {
+            Some(encoding) => Some(encoding),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Unsupported encoding: {name}"),
+                )
+                    .into_response()
+            }
+        },
+        None => None,
+    };
+
+    // Reserved before upgrading so simultaneous connects can't overshoot
+    let slot = state.acquire_connection(ip);
+
+    ws.protocols(Encoding::SUBPROTOCOLS)
+        .on_upgrade(move |socket| async move {
+            let encoding = requested.unwrap_or_else(|| {
+                socket
+                    .protocol()
+                    .and_then(|protocol| protocol.to_str().ok())
+                    .and_then(Encoding::from_name)
+                    .unwrap_or_default()
+            });
+            match slot {
+                Some(slot) => handle_socket(socket, state, encoding, grant, ip, slot).await,
+                None => {
+                    warn!("Rejecting WebSocket from {}: too many connections", ip);
+                    reject_socket(socket, encoding).await;
+                }
+            }
+        })
+}
+
+/// Tell a client it is over the connection limit, then close
+///
+/// Done after the upgrade because browsers don't expose a failed
+/// handshake's response to scripts.
+async fn reject_socket(mut socket: WebSocket, encoding: Encoding) {
+    let message = ServerMessage::Error {
+        id: None,
+        code: ErrorCode::TooManyConnections,
+        message: "Too many connections from this address".to_string(),
+        match_id: None,
+        request_id: None,
+    };
+    if let Ok(frame) = encoding.encode(&message) {
+        let _ = socket.send(frame).await;
+    }
+    let _ = socket
+        .send(Message::Close(Some(CloseFrame {
+            code: close_code::POLICY,
+            reason: "too many connections".into(),
+        })))
+        .await;
 }
 
 /// Handle individual WebSocket connection
-async fn handle_socket(socket: WebSocket, state: WsState) {
+async fn handle_socket(
+    socket: WebSocket,
+    state: WsState,
+    encoding: Encoding,
+    grant: Grant,
+    ip: IpAddr,
+    slot: ConnectionSlot,
+) {
+    debug!(
+        "WebSocket connected as {} with {:?} encoding",
+        grant.name, encoding
+    );
     let (sender, receiver) = socket.split();
 
     // Spawn a task to handle incoming messages with panic recovery
     let state_clone = state.clone();
+    let gauge = ConnectionGauge::new();
     tokio::spawn(async move {
         // Catch any panics in the WebSocket handler
-        let result =
-            std::panic::AssertUnwindSafe(handle_client_messages(receiver, sender, state_clone))
-                .catch_unwind()
-                .await;
+        let result = std::panic::AssertUnwindSafe(handle_client_messages(
+            receiver,
+            sender,
+            state_clone,
+            encoding,
+            grant,
+            ip,
+        ))
+        .catch_unwind()
+        .await;
 
         match result {
             Ok(Ok(())) => {
@@ -88,42 +753,235 @@ async fn handle_socket(socket: WebSocket, state: WsState) {
                 error!("WebSocket handler panicked: {:?}", panic);
             }
         }
+
+        // Free the slot only once the connection is fully gone
+        drop(slot);
+        drop(gauge);
     });
 }
 
+/// Subscription to one match on a connection
+struct Subscription {
+    /// Task forwarding the match's broadcasts into the outbox
+    task: JoinHandle<()>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// What was agreed with the client in `hello`
+struct Protocol {
+    version: u32,
+    features: Vec<Feature>,
+}
+
+/// Per-connection state shared by the reader loop and forwarding tasks
+struct Connection {
+    /// Unique within this process run; prefixes request IDs
+    id: u64,
+    state: WsState,
+    ip: IpAddr,
+    encoding: Encoding,
+    /// Unix time in milliseconds
+    connected_at: u64,
+    /// Frame payload bytes written so far
+    bytes_sent: Arc<AtomicU64>,
+    /// What the client's credentials allow
+    grant: Grant,
+    /// Key for the inbound message limit, which is per connection so users
+    /// sharing an IP or API key don't use up each other's allowance
+    rate_key: String,
+    outbox: Arc<Outbox>,
+    /// Agreed in `hello`; unset means version 0 with no features
+    protocol: OnceLock<Protocol>,
+    /// match_id -> subscription
+    subscriptions: RwLock<HashMap<String, Subscription>>,
+    /// Live matches list subscription, if any
+    list_subscription: RwLock<Option<Subscription>>,
+}
+
+/// IDs of the client message being handled
+struct Request {
+    /// Position of the message on the connection (starts at 1)
+    seq: u64,
+    /// Assigned by the server; appears in logs
+    request_id: String,
+    /// Supplied by the client; echoed in the reply
+    id: Option<String>,
+}
+
+impl Connection {
+    async fn info(&self) -> ConnectionInfo {
+        let mut subscriptions: Vec<String> =
+            self.subscriptions.read().await.keys().cloned().collect();
+        subscriptions.sort();
+        ConnectionInfo {
+            id: self.id,
+            remote_addr: self.ip,
+            client: self.grant.name.clone(),
+            encoding: self.encoding.name(),
+            protocol_version: self.protocol_version(),
+            features: self
+                .protocol
+                .get()
+                .map(|protocol| protocol.features.clone())
+                .unwrap_or_default(),
+            connected_at: self.connected_at,
+            subscriptions,
+            list_subscribed: self.list_subscription.read().await.is_some(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+
+    fn protocol_version(&self) -> u32 {
+        self.protocol.get().map_or(0, |protocol| protocol.version)
+    }
+
+    fn has_feature(&self, feature: Feature) -> bool {
+        self.protocol
+            .get()
+            .is_some_and(|protocol| protocol.features.contains(&feature))
+    }
+
+    /// Reply with an `error` message
+    fn send_error(&self, req: &Request, code: ErrorCode, message: String, match_id: Option<&str>) {
+        self.outbox.send(ServerMessage::Error {
+            id: req.id.clone(),
+            code,
+            message,
+            match_id: match_id.map(str::to_string),
+            request_id: Some(req.request_id.clone()),
+        });
+    }
+
+    /// Reply to a request that failed in Redis, keeping internals in the logs
+    fn send_failure(&self, req: &Request, e: &RedisError, match_id: Option<&str>) {
+        let code = e.code();
+        let message = if code == ErrorCode::Internal {
+            error!("Request {} failed: {}", req.request_id, e);
+            "Internal server error".to_string()
+        } else {
+            e.to_string()
+        };
+        self.send_error(req, code, message, match_id);
+    }
+}
+
 /// Handle incoming WebSocket messages from client
 async fn handle_client_messages(
     mut receiver: SplitStream<WebSocket>,
-    mut sender: SplitSink<WebSocket, Message>,
+    sender: SplitSink<WebSocket, Message>,
     state: WsState,
+    encoding: Encoding,
+    grant: Grant,
+    ip: IpAddr,
 ) -> anyhow::Result<()> {
-    // Track subscriptions for this connection
-    let subscriptions: Arc<RwLock<HashSet<String>>> = Arc::new(RwLock::new(HashSet::new()));
+    let outbox = Arc::new(Outbox::new(
+        state.config.send_queue_size,
+        state.config.slow_client_policy,
+    ));
+    let bytes_sent = Arc::new(AtomicU64::new(0));
+    let id = state.next_connection_id.fetch_add(1, Ordering::Relaxed);
+    let conn = Arc::new(Connection {
+        id,
+        state: state.clone(),
+        ip,
+        encoding,
+        connected_at: unix_millis(),
+        bytes_sent: bytes_sent.clone(),
+        rate_key: format!("conn:{id}"),
+        grant,
+        outbox: outbox.clone(),
+        protocol: OnceLock::new(),
+        subscriptions: RwLock::new(HashMap::new()),
+        list_subscription: RwLock::new(None),
+    });
+    state
+        .connections
+        .write()
+        .await
+        .insert(conn.id, conn.clone());
 
-    // Channels for each subscribed match
-    let receivers: Arc<RwLock<HashMap<String, broadcast::Receiver<ServerMessage>>>> =
-        Arc::new(RwLock::new(HashMap::new()));
+    // Writes happen on their own task so a slow client never blocks reads
+    let writer = tokio::spawn(write_outbox(sender, outbox.clone(), encoding, bytes_sent));
+
+    // Server-side heartbeat; anything from the client counts as alive
+    let ping_interval = state.config.ping_interval;
+    let mut heartbeat =
+        tokio::time::interval_at(tokio::time::Instant::now() + ping_interval, ping_interval);
+    heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_seen = Instant::now();
+    let mut requests = 0u64;
+    // Until when the client has been told it is rate limited
+    let mut rate_limited_until = Instant::now();
 
     loop {
         tokio::select! {
             // Handle incoming messages from client
             msg = receiver.next() => {
+                if matches!(msg, Some(Ok(_))) {
+                    last_seen = Instant::now();
+                }
+
+                // Data frames and pings take a token before any work is done,
+                // so nothing gets queued for frames over the limit
+                if matches!(msg, Some(Ok(Message::Text(_) | Message::Binary(_) | Message::Ping(_)))) {
+                    if let Err(retry_after) = state.message_limiter.check(&conn.rate_key) {
+                        // Dropped pings go unanswered; data frames get one
+                        // error per refill window rather than one each
+                        if !matches!(msg, Some(Ok(Message::Ping(_)))) {
+                            requests += 1;
+                            let now = Instant::now();
+                            if now >= rate_limited_until {
+                                rate_limited_until = now + retry_after;
+                                let req = Request {
+                                    seq: requests,
+                                    request_id: format!("{}-{}", conn.id, requests),
+                                    id: None,
+                                };
+                                conn.send_error(
+                                    &req,
+                                    ErrorCode::RateLimited,
+                                    format!(
+                                        "Too many messages; retry in {}ms",
+                                        retry_after.as_millis()
+                                    ),
+                                    None,
+                                );
+                            }
+                        }
+                        continue;
+                    }
+                }
+
                 match msg {
-                    Some(Ok(Message::Text(text))) => {
-                        if let Err(e) = handle_text_message(
-                            &text,
-                            &state,
-                            &subscriptions,
-                            &receivers,
-                            &mut sender,
-                        ).await {
-                            error!("Error handling message: {}", e);
-                            // Send error to client but don't disconnect
-                            let error_msg = ServerMessage::Error {
-                                message: format!("Failed to process message: {e}"),
-                            };
-                            if let Ok(json) = serde_json::to_string(&error_msg) {
-                                let _ = sender.send(Message::Text(json)).await;
+                    Some(Ok(frame @ (Message::Text(_) | Message::Binary(_)))) => {
+                        requests += 1;
+                        let mut req = Request {
+                            seq: requests,
+                            request_id: format!("{}-{}", conn.id, requests),
+                            id: None,
+                        };
+
+                        // Errors are reported to the client without disconnecting
+                        match encoding.decode(&frame) {
+                            Ok(client_msg) => {
+                                req.id = client_msg.id().map(str::to_string);
+                                if let Err(e) = handle_client_message(client_msg, &conn, &req).await {
+                                    conn.send_failure(&req, &e, None);
+                                }
+                            }
+                            Err(e) => {
+                                debug!("Invalid message {}: {}", req.request_id, e);
+                                conn.send_error(
+                                    &req,
+                                    ErrorCode::InvalidMessage,
+                                    format!("Invalid message: {e}"),
+                                    None,
+                                );
                             }
                         }
                     }
@@ -133,10 +991,7 @@ async fn handle_client_messages(
                     }
                     Some(Ok(Message::Ping(data))) => {
                         // Respond to ping with pong
-                        if let Err(e) = sender.send(Message::Pong(data)).await {
-                            error!("Failed to send pong: {}", e);
-                            break;
-                        }
+                        outbox.send_frame(Message::Pong(data));
                     }
                     Some(Err(e)) => {
                         error!("WebSocket error: {}", e);
@@ -150,79 +1005,456 @@ async fn handle_client_messages(
                 }
             }
 
-            // Handle broadcast messages for all subscriptions
-            _ = async {
-                let mut rcvs = receivers.write().await;
-                for (_match_id, rx) in rcvs.iter_mut() {
-                    if let Ok(msg) = rx.try_recv() {
-                        if let Ok(json) = serde_json::to_string(&msg) {
-                            if let Err(e) = sender.send(Message::Text(json)).await {
-                                error!("Failed to send broadcast message: {}", e);
-                                // Don't break - try to continue
-                            }
-                        }
-                    }
+            // Ping the client and drop it if it has gone quiet
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() >= state.config.idle_timeout {
+                    info!("Closing idle WebSocket connection");
+                    outbox.close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "idle timeout".into(),
+                    }));
+                    break;
                 }
-            } => {}
+
+                outbox.send_frame(Message::Ping(Vec::new()));
+                outbox.send(ServerMessage::Heartbeat {
+                    server_time: unix_millis(),
+                    interval_secs: ping_interval.as_secs(),
+                });
+            }
+
+            // Server is going away: say when to come back, then close
+            _ = state.shutting_down() => {
+                let reconnect_after = state.reconnect_hint();
+                outbox.send(ServerMessage::Shutdown {
+                    reconnect_after_ms: reconnect_after.as_millis() as u64,
+                });
+                outbox.close(Some(CloseFrame {
+                    code: close_code::RESTART,
+                    reason: "server shutting down".into(),
+                }));
+                break;
+            }
+
+            // Closed from elsewhere, e.g. a slow client being dropped
+            _ = outbox.closed() => break,
         }
     }
 
     // Cleanup: unsubscribe from all channels
-    info!("Cleaning up WebSocket connection");
+    info!(
+        "Cleaning up WebSocket connection {} (protocol v{})",
+        conn.id,
+        conn.protocol_version()
+    );
+    conn.subscriptions.write().await.clear();
+    *conn.list_subscription.write().await = None;
+    outbox.close(None);
+
+    // Still counted until the close frame is written, so shutdown waits for it
+    let written = writer.await;
+    state.connections.write().await.remove(&conn.id);
+    written??;
     Ok(())
 }
 
-/// Handle text message from client
-async fn handle_text_message(
-    text: &str,
-    state: &WsState,
-    subscriptions: &Arc<RwLock<HashSet<String>>>,
-    receivers: &Arc<RwLock<HashMap<String, broadcast::Receiver<ServerMessage>>>>,
-    sender: &mut SplitSink<WebSocket, Message>,
+/// Write queued messages to the socket until the outbox is closed
+async fn write_outbox(
+    mut sender: SplitSink<WebSocket, Message>,
+    outbox: Arc<Outbox>,
+    encoding: Encoding,
+    bytes_sent: Arc<AtomicU64>,
 ) -> anyhow::Result<()> {
-    let client_msg: ClientMessage = serde_json::from_str(text)?;
+    while let Some(item) = outbox.next().await {
+        let mut created = None;
+        let frame = match item {
+            Outgoing::Direct(message) => encoding.encode(&message),
+            Outgoing::Event { event, .. } => {
+                created = Some(event.created);
+                event.frame(encoding)
+            }
+            Outgoing::Frame(frame) => Ok(frame),
+        };
+
+        match frame {
+            Ok(frame) => {
+                let len = frame_len(&frame);
+                if let Err(e) = sender.send(frame).await {
+                    // Socket is gone; stop the reader too
+                    debug!("Failed to send WebSocket message: {}", e);
+                    outbox.close(None);
+                    break;
+                }
+                bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+                if let Some(created) = created {
+                    metrics::BROADCAST_DELIVERY.observe(created.elapsed().as_secs_f64());
+                }
+            }
+            Err(e) => error!("Failed to encode WebSocket message: {}", e),
+        }
+    }
+
+    let _ = sender.close().await;
+    Ok(())
+}
+
+/// Payload size of a frame
+fn frame_len(frame: &Message) -> usize {
+    match frame {
+        Message::Text(text) => text.len(),
+        Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close(frame) => frame.as_ref().map_or(0, |frame| 2 + frame.reason.len()),
+    }
+}
+
+/// Forward a match's broadcasts into a connection's outbox
+async fn forward_match(
+    match_id: String,
+    topics: Vec<Topic>,
+    mut rx: broadcast::Receiver<MatchEvent>,
+    outbox: Arc<Outbox>,
+    state: WsState,
+) {
+    while !outbox.is_closed() {
+        match rx.recv().await {
+            Ok(event) => {
+                // Skip topics this client didn't ask for
+                if event.message.matches_topics(&topics) {
+                    outbox.send_event(&match_id, event);
+                }
+            }
+            // Fell behind the channel: resend the whole state
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                debug!(
+                    "Subscriber lagged by {} events for match {}",
+                    skipped, match_id
+                );
+                metrics::BROADCAST_DROPPED
+                    .with_label_values(&["lagged"])
+                    .inc_by(skipped);
+                outbox.send(ServerMessage::Resync {
+                    match_id: match_id.clone(),
+                    skipped,
+                });
+                match state.redis.get_full_match_state(&match_id).await {
+                    Ok(full_state) => outbox.send(ServerMessage::FullState {
+                        data: Box::new(full_state),
+                    }),
+                    Err(e) => error!("Failed to get match state for resync: {}", e),
+                }
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Forward live matches list changes into a connection's outbox
+async fn forward_list(
+    mut rx: broadcast::Receiver<ServerMessage>,
+    outbox: Arc<Outbox>,
+    state: WsState,
+    grant: Grant,
+) {
+    while !outbox.is_closed() {
+        match rx.recv().await {
+            Ok(message) => {
+                // Only changes to matches the client may see
+                if let ServerMessage::ListUpdate { data, .. } = &message {
+                    if !grant.allows_summary(data) {
+                        continue;
+                    }
+                }
+                outbox.send(message);
+            }
+            // Missed some changes: resend the whole list
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                match state.redis.get_live_matches().await {
+                    Ok(mut matches) => {
+                        matches.retain(|summary| grant.allows_summary(summary));
+                        outbox.send(ServerMessage::MatchList { data: matches });
+                    }
+                    Err(e) => error!("Failed to refresh live matches list: {}", e),
+                }
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Handle a decoded message from client
+async fn handle_client_message(
+    client_msg: ClientMessage,
+    conn: &Connection,
+    req: &Request,
+) -> Result<(), RedisError> {
+    let state = &conn.state;
 
     match client_msg {
-        ClientMessage::Subscribe { match_id } => {
-            debug!("Client subscribing to match: {}", match_id);
+        ClientMessage::Hello {
+            id,
+            version,
+            features,
+        } => {
+            // Later hellos could change the rules under messages already handled
+            if req.seq != 1 {
+                conn.send_error(
+                    req,
+                    ErrorCode::InvalidMessage,
+                    "hello must be the first message".to_string(),
+                    None,
+                );
+                return Ok(());
+            }
 
-            // Add to subscriptions
-            subscriptions.write().await.insert(match_id.clone());
+            // Speak the older of the two versions, with the features both know
+            let version = version.min(PROTOCOL_VERSION);
+            let features: Vec<Feature> = Feature::SUPPORTED
+                .into_iter()
+                .filter(|feature| features.contains(feature))
+                .collect();
+            debug!("Client hello: protocol v{} {:?}", version, features);
+            conn.outbox.set_version(version);
+            conn.outbox.send(ServerMessage::Hello {
+                id,
+                version,
+                features: features.clone(),
+            });
+            let _ = conn.protocol.set(Protocol { version, features });
+        }
+
+        ClientMessage::Subscribe {
+            id,
+            match_id,
+            topics,
+        } => {
+            debug!("Client subscribing to match: {} {:?}", match_id, topics);
+
+            if !topics.is_empty() && !conn.has_feature(Feature::Topics) {
+                conn.send_error(
+                    req,
+                    ErrorCode::InvalidMessage,
+                    "topics need the topics feature; send hello first".to_string(),
+                    Some(&match_id),
+                );
+                return Ok(());
+            }
+
+            // Re-subscribing to a match doesn't count towards the limit
+            let limit = state.config.max_subscriptions;
+            {
+                let subscriptions = conn.subscriptions.read().await;
+                if limit > 0
+                    && subscriptions.len() >= limit
+                    && !subscriptions.contains_key(&match_id)
+                {
+                    conn.send_error(
+                        req,
+                        ErrorCode::TooManySubscriptions,
+                        format!("Subscription limit of {limit} matches reached"),
+                        Some(&match_id),
+                    );
+                    return Ok(());
+                }
+            }
+
+            match state.authorize(&conn.grant, &match_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    conn.send_error(
+                        req,
+                        ErrorCode::Unauthorized,
+                        format!("Not allowed to subscribe to match {match_id}"),
+                        Some(&match_id),
+                    );
+                    return Ok(());
+                }
+                Err(e) => {
+                    conn.send_failure(req, &e, Some(&match_id));
+                    return Ok(());
+                }
+            }
 
             // Get or create channel
-            let tx = state.get_or_create_channel(&match_id).await;
-            let rx = tx.subscribe();
-            receivers.write().await.insert(match_id.clone(), rx);
+            let channel = match state.get_or_create_channel(&match_id).await {
+                Ok(Some(channel)) => channel,
+                Ok(None) => {
+                    let e = RedisError::MatchNotFound(match_id.clone());
+                    conn.send_failure(req, &e, Some(&match_id));
+                    return Ok(());
+                }
+                Err(e) => {
+                    conn.send_failure(req, &e, Some(&match_id));
+                    return Ok(());
+                }
+            };
+            let (rx, _) = channel.subscribe();
 
-            // Fetch and send full state
+            // Fetch and send full state after the ack
             match state.redis.get_full_match_state(&match_id).await {
                 Ok(full_state) => {
-                    let msg = ServerMessage::FullState { data: Box::new(full_state) };
-                    let json = serde_json::to_string(&msg)?;
-                    sender.send(Message::Text(json)).await?;
+                    conn.outbox.send(ServerMessage::Subscribed {
+                        id,
+                        match_id: Some(match_id.clone()),
+                        topics: topics.clone(),
+                    });
+                    conn.outbox.send(ServerMessage::FullState {
+                        data: Box::new(full_state),
+                    });
                     info!("Sent full state for match: {}", match_id);
                 }
                 Err(e) => {
-                    error!("Failed to get match state: {}", e);
-                    let error_msg = ServerMessage::Error {
-                        message: format!("Failed to get match state: {e}"),
-                    };
-                    let json = serde_json::to_string(&error_msg)?;
-                    sender.send(Message::Text(json)).await?;
+                    // Not subscribed, so the client can simply retry
+                    conn.send_failure(req, &e, Some(&match_id));
+                    return Ok(());
                 }
             }
+
+            // Start forwarding after the full state so updates follow it
+            // (re-subscribing replaces the topics)
+            let task = tokio::spawn(forward_match(
+                match_id.clone(),
+                topics,
+                rx,
+                conn.outbox.clone(),
+                state.clone(),
+            ));
+            conn.subscriptions
+                .write()
+                .await
+                .insert(match_id, Subscription { task });
         }
 
-        ClientMessage::Unsubscribe { match_id } => {
+        ClientMessage::Unsubscribe { id, match_id } => {
             debug!("Client unsubscribing from match: {}", match_id);
 
             // Remove from subscriptions
-            subscriptions.write().await.remove(&match_id);
-            receivers.write().await.remove(&match_id);
+            conn.subscriptions.write().await.remove(&match_id);
 
+            // Acknowledged even if it wasn't subscribed; the end state is the same
+            conn.outbox.send(ServerMessage::Unsubscribed {
+                id,
+                match_id: Some(match_id.clone()),
+            });
             info!("Client unsubscribed from match: {}", match_id);
         }
+
+        ClientMessage::SubscribeList { id } => {
+            debug!("Client subscribing to live matches list");
+
+            let (rx, mut matches) = state.subscribe_list().await?;
+            matches.retain(|summary| conn.grant.allows_summary(summary));
+            conn.outbox.send(ServerMessage::Subscribed {
+                id,
+                match_id: None,
+                topics: Vec::new(),
+            });
+            conn.outbox.send(ServerMessage::MatchList { data: matches });
+            info!("Sent live matches list");
+
+            let task = tokio::spawn(forward_list(
+                rx,
+                conn.outbox.clone(),
+                state.clone(),
+                conn.grant.clone(),
+            ));
+            *conn.list_subscription.write().await = Some(Subscription { task });
+        }
+
+        ClientMessage::UnsubscribeList { id } => {
+            debug!("Client unsubscribing from live matches list");
+            *conn.list_subscription.write().await = None;
+            conn.outbox
+                .send(ServerMessage::Unsubscribed { id, match_id: None });
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(first_seq: u64) -> MatchChannel {
+        MatchChannel::new(16, first_seq)
+    }
+
+    fn send(channel: &MatchChannel, count: usize) {
+        for _ in 0..count {
+            channel.send(ServerMessage::Viewers {
+                match_id: "m1".to_string(),
+                viewers: 1,
+            });
+        }
+    }
+
+    fn seqs(events: Option<Vec<MatchEvent>>) -> Option<Vec<u64>> {
+        events.map(|events| events.iter().map(|event| event.seq).collect())
+    }
+
+    #[test]
+    fn subscribe_after_replays_missed_events() {
+        let channel = channel(1);
+        send(&channel, 3);
+        let (_rx, latest, missed) = channel.subscribe_after(1);
+        assert_eq!(latest, 3);
+        assert_eq!(seqs(missed), Some(vec![2, 3]));
+    }
+
+    #[test]
+    fn subscribe_after_latest_replays_nothing() {
+        let channel = channel(1);
+        send(&channel, 3);
+        assert_eq!(seqs(channel.subscribe_after(3).2), Some(vec![]));
+
+        // Nothing sent yet: resuming from 0 is up to date
+        let empty = MatchChannel::new(16, 1);
+        assert_eq!(seqs(empty.subscribe_after(0).2), Some(vec![]));
+    }
+
+    #[test]
+    fn subscribe_after_unknown_future_seq_needs_full_state() {
+        let channel = channel(1);
+        send(&channel, 3);
+        assert_eq!(seqs(channel.subscribe_after(4).2), None);
+    }
+
+    #[test]
+    fn subscribe_after_history_boundary() {
+        let channel = channel(1);
+        send(&channel, HISTORY_SIZE + 5);
+        let oldest = 6;
+
+        // The event right after the one the client has is still kept
+        let missed = seqs(channel.subscribe_after(oldest - 1).2).unwrap();
+        assert_eq!(missed.len(), HISTORY_SIZE);
+        assert_eq!(missed.first(), Some(&oldest));
+
+        // One further back and there's a gap
+        assert_eq!(seqs(channel.subscribe_after(oldest - 2).2), None);
+    }
+
+    #[test]
+    fn recreated_channel_rejects_ids_from_the_old_one() {
+        // Dropped channel had sent up to seq 9 (next_seq 10); see prune_channels
+        let channel = channel(11);
+        assert_eq!(seqs(channel.subscribe_after(9).2), None);
+        send(&channel, 2);
+        assert_eq!(seqs(channel.subscribe_after(9).2), None);
+        assert_eq!(seqs(channel.subscribe_after(11).2), Some(vec![12]));
+    }
+
+    #[test]
+    fn transient_messages_take_no_seq_or_history() {
+        let channel = channel(1);
+        send(&channel, 2);
+        let (mut rx, _) = channel.subscribe();
+        channel.send_transient(ServerMessage::Viewers {
+            match_id: "m1".to_string(),
+            viewers: 5,
+        });
+        assert_eq!(channel.latest_seq(), 2);
+        assert_eq!(rx.try_recv().unwrap().seq, 2);
+        assert_eq!(seqs(channel.subscribe_after(0).2), Some(vec![1, 2]));
+    }
+}